@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use midly::number::u7;
+
+use crate::processor::{self, ControlInterface};
+use crate::theory::{Note, Pitch31};
+use crate::tonal_space::{AssonanceMetric, ProjectionType, TonalSpace, TonalSpaceConfig};
+
+/// A sequence of MIDI keys with the spelling each of them should get.
+///
+/// Corpus files start with optional `name = value` settings (`projection`, `metric` and
+/// `min_accuracy` in percent), followed by the notes as `key:pitch` pairs in the order they are
+/// played, e.g. `60:C4 64:E4 67:G4`. Line breaks between notes are only for readability, and
+/// lines starting with # are comments.
+///
+/// Spellings the heuristic is known to get wrong are written after the expected one, e.g. `63:Eb4(Ebb4)`.
+/// A corpus fails if any other note is spelled wrongly, or a known mistake turns into a different one.
+pub struct Corpus {
+    pub projection_type: ProjectionType,
+    pub metric: AssonanceMetric,
+
+    /// Accuracy the spelling must reach for the corpus to pass, in percent
+    pub min_accuracy: f64,
+
+    /// Expected spellings, with the line they are written on and the known wrong spelling, if any
+    pub notes: Vec<(usize, u7, Pitch31, Option<Pitch31>)>
+}
+
+/// A note that was spelled differently than expected
+pub struct Mismatch {
+    pub line: usize,
+    pub key: u8,
+    pub expected: Pitch31,
+    pub got: Pitch31,
+
+    /// Whether `got` is the spelling the corpus lists as a known mistake
+    pub known: bool
+}
+
+pub struct Evaluation {
+    pub total: usize,
+    pub mismatches: Vec<Mismatch>
+}
+
+impl Evaluation {
+    /// Percentage of notes spelled as expected
+    pub fn accuracy(&self) -> f64 {
+        if self.total == 0 {
+            return 100.0;
+        }
+        (self.total - self.mismatches.len()) as f64 * 100.0 / self.total as f64
+    }
+
+    /// Mismatches that are not listed as known mistakes in the corpus
+    pub fn unexpected(&self) -> impl Iterator<Item = &Mismatch> {
+        self.mismatches.iter().filter(|m| !m.known)
+    }
+}
+
+/// Counts how often each written note was spelled as each note
+#[derive(Default)]
+pub struct ConfusionMatrix {
+    counts: HashMap<(Note, Note), usize>
+}
+
+impl ConfusionMatrix {
+    pub fn add(&mut self, written: Note, chosen: Note) {
+        *self.counts.entry((written, chosen)).or_insert(0) += 1;
+    }
+}
+
+impl fmt::Display for ConfusionMatrix {
+    /// Writes a table with a row for each written note and a column for each chosen note,
+    /// both in chain of fifths order
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut notes = self.counts.keys().flat_map(|(w, c)| vec![*w, *c]).collect::<Vec<_>>();
+        notes.sort_by_key(|n| n.chain_position());
+        notes.dedup();
+
+        write!(f, "written\\chosen")?;
+        for chosen in &notes {
+            write!(f, "{:>5}", chosen.to_string())?;
+        }
+        writeln!(f)?;
+        for written in &notes {
+            write!(f, "{:<14}", written.to_string())?;
+            for chosen in &notes {
+                match self.counts.get(&(*written, *chosen)) {
+                    Some(count) => write!(f, "{:>5}", count)?,
+                    None => write!(f, "{:>5}", ".")?
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl Corpus {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::parse(&source).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut corpus = Corpus {
+            projection_type: ProjectionType::Meantone31KeepUnison,
+            metric: AssonanceMetric::Pythagorean,
+            min_accuracy: 0.0,
+            notes: vec![]
+        };
+
+        for (i, line) in source.lines().enumerate() {
+            let line = line.trim();
+            let error = |e: String| format!("line {}: {}", i + 1, e);
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some((name, value)) = line.split_once('=') {
+                if !corpus.notes.is_empty() {
+                    return Err(error("settings must come before the notes".to_owned()));
+                }
+                let value = value.trim();
+                match name.trim() {
+                    "projection" => corpus.projection_type = ProjectionType::from_name(value)
+                        .ok_or_else(|| error(format!("unknown projection type: {}", value)))?,
+                    "metric" => corpus.metric = AssonanceMetric::from_name(value)
+                        .ok_or_else(|| error(format!("unknown assonance metric: {}", value)))?,
+                    "min_accuracy" => corpus.min_accuracy = value.parse()
+                        .map_err(|_| error(format!("invalid accuracy: {}", value)))?,
+                    name => return Err(error(format!("unknown setting: {}", name)))
+                }
+                continue;
+            }
+
+            for token in line.split_whitespace() {
+                let (key, pitch) = token.split_once(':')
+                    .ok_or_else(|| error(format!("expected <key>:<pitch>, found {}", token)))?;
+                let key = key.parse::<u8>().ok()
+                    .and_then(u7::try_from)
+                    .ok_or_else(|| error(format!("invalid MIDI key: {}", key)))?;
+                let (pitch, known) = match pitch.strip_suffix(')').and_then(|p| p.split_once('(')) {
+                    Some((pitch, known)) => (pitch, Some(Pitch31::new(known).map_err(error)?)),
+                    None => (pitch, None)
+                };
+                let pitch = Pitch31::new(pitch).map_err(error)?;
+                corpus.notes.push((i + 1, key, pitch, known));
+            }
+        }
+        Ok(corpus)
+    }
+
+    /// Spells the notes in order with a fresh tonal space and compares them with the expected spellings
+    pub fn evaluate(&self, config: TonalSpaceConfig) -> Evaluation {
+        let mut tonal_space = TonalSpace::new(config);
+        let control = ControlInterface::default();
+
+        let mismatches = self.notes.iter()
+            .filter_map(|&(line, key, expected, known)| {
+                let got = processor::convert_to_31(key, &mut tonal_space, self.metric, self.projection_type, &control);
+                if got == expected {
+                    None
+                } else {
+                    Some(Mismatch { line, key: key.as_int(), expected, got, known: known == Some(got) })
+                }
+            })
+            .collect();
+
+        Evaluation { total: self.notes.len(), mismatches }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs every corpus under tests/corpus and checks it reaches its minimum accuracy, without any
+    /// mistakes beyond the known ones
+    #[test]
+    fn golden_corpus() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+        let mut files = fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "txt"))
+            .collect::<Vec<_>>();
+        files.sort();
+        assert!(!files.is_empty());
+
+        for file in files {
+            let corpus = Corpus::from_file(&file).unwrap();
+            let evaluation = corpus.evaluate(TonalSpaceConfig::default());
+            for m in evaluation.unexpected() {
+                println!("{}:{}: key {}: expected {}, got {}", file.display(), m.line, m.key, m.expected, m.got);
+            }
+            assert_eq!(evaluation.unexpected().count(), 0, "{}: unexpected spellings", file.display());
+            assert!(evaluation.accuracy() >= corpus.min_accuracy, "{}: accuracy {:.1}% is below {}%",
+                    file.display(), evaluation.accuracy(), corpus.min_accuracy);
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Corpus::parse("60:C4\nmin_accuracy = 50").is_err());
+        assert!(Corpus::parse("projection = Nope").is_err());
+        assert!(Corpus::parse("60 C4").is_err());
+        assert!(Corpus::parse("128:C4").is_err());
+        assert!(Corpus::parse("63:Eb4(Ebb)").is_err());
+
+        let corpus = Corpus::parse("60:C4 63:Eb4(Ebb4)").unwrap();
+        assert_eq!(corpus.notes[1].3, Some(Pitch31::new("Ebb4").unwrap()));
+    }
+}
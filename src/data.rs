@@ -1,5 +1,26 @@
 use crate::theory::Pitch31;
 
 pub struct ActiveNote {
-    pitch: Pitch31,
-}
\ No newline at end of file
+    /// Spelling chosen for the note
+    pub pitch: Pitch31,
+
+    /// Output channel the note is sounding on
+    pub channel: u8,
+
+    /// Output key the note is sounding on
+    pub key: u8,
+
+    /// Cents `key` is retuned by to reach the chosen pitch, without the pitch bend wheel
+    pub cents: f64,
+}
+
+impl ActiveNote {
+    pub fn new(pitch: Pitch31, channel: u8, key: u8, cents: f64) -> Self {
+        ActiveNote {
+            pitch,
+            channel,
+            key,
+            cents
+        }
+    }
+}
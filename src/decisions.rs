@@ -0,0 +1,104 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::theory::Pitch31;
+use crate::tui;
+
+/// Everything that went into retuning a note on, see `Processor::last_decision`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Decision {
+    pub input: usize,
+
+    /// Channel (0 based), key and velocity of the incoming note on
+    pub channel: u8,
+    pub key: u8,
+    pub velocity: u8,
+
+    /// Candidate spellings with their scores, best first. Empty with static spelling.
+    pub candidates: Vec<(Pitch31, f64)>,
+
+    pub pitch: Pitch31,
+
+    /// Channel (0 based), key and pitch bend the note was sent with
+    pub out_channel: u8,
+    pub out_key: u8,
+    pub bend: u16,
+
+    /// Pitches of the tonal space the note was spelled against, with their recency weight
+    pub tonal_space: Vec<(Pitch31, f64)>
+}
+
+impl Decision {
+    /// Writes the decision as a single line JSON object. Channels are numbered 1 to 16.
+    ///
+    /// Written by hand rather than with serde, as the log is available without the serde feature,
+    /// which only brings the derive macros and no JSON support.
+    pub fn to_json(&self, stamp: u64) -> String {
+        let pitches = |list: &[(Pitch31, f64)], value: &str| list.iter()
+            .map(|(p, v)| format!("{{\"pitch\":\"{}\",\"{}\":{}}}", p, value, number(*v)))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        format!("{{\"timestamp_us\":{},\"input\":{},\"channel\":{},\"key\":{},\"velocity\":{},\"candidates\":[{}],\
+                 \"pitch\":\"{}\",\"output\":{{\"channel\":{},\"key\":{},\"bend\":{}}},\"tonal_space\":[{}]}}",
+                stamp, self.input, self.channel + 1, self.key, self.velocity, pitches(&self.candidates, "score"),
+                self.pitch, self.out_channel + 1, self.out_key, self.bend, pitches(&self.tonal_space, "weight"))
+    }
+}
+
+/// JSON has no infinities or NaN
+fn number(x: f64) -> String {
+    if x.is_finite() { x.to_string() } else { "null".to_owned() }
+}
+
+/// Writes decisions as JSON lines to a file or stdout. Every line is flushed right away, so that
+/// nothing is lost when live mode is killed.
+pub struct DecisionLog {
+    out: Box<dyn Write + Send>
+}
+
+impl DecisionLog {
+    /// Logs to the file `path`, or to stdout if it is "-"
+    pub fn create(path: &Path) -> Result<Self, String> {
+        let out: Box<dyn Write + Send> = if path == Path::new("-") {
+            Box::new(io::stdout())
+        } else {
+            Box::new(BufWriter::new(File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?))
+        };
+        Ok(DecisionLog { out })
+    }
+
+    pub fn write(&mut self, stamp: u64, decision: &Decision) {
+        writeln!(self.out, "{}", decision.to_json(stamp))
+            .and_then(|_| self.out.flush())
+            .unwrap_or_else(|e| tui::report(format!("Error when logging decision: {}", e)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decision_json() {
+        let pitch = |name| Pitch31::new(name).unwrap();
+        let decision = Decision {
+            input: 0,
+            channel: 0,
+            key: 63,
+            velocity: 100,
+            candidates: vec![(pitch("Eb4"), 2.5), (pitch("D#4"), f64::INFINITY)],
+            pitch: pitch("Eb4"),
+            out_channel: 1,
+            out_key: 63,
+            bend: 8192,
+            tonal_space: vec![(pitch("C4"), 1.0)]
+        };
+        assert_eq!(decision.to_json(1500),
+                   "{\"timestamp_us\":1500,\"input\":0,\"channel\":1,\"key\":63,\"velocity\":100,\
+                    \"candidates\":[{\"pitch\":\"Eb4\",\"score\":2.5},{\"pitch\":\"D#4\",\"score\":null}],\
+                    \"pitch\":\"Eb4\",\"output\":{\"channel\":2,\"key\":63,\"bend\":8192},\
+                    \"tonal_space\":[{\"pitch\":\"C4\",\"weight\":1}]}");
+    }
+}
@@ -4,84 +4,937 @@ extern crate midly;
 extern crate lazy_static;
 extern crate regex;
 
+mod corpus;
+mod midi_file;
+mod musicxml;
+mod output;
+mod preset;
 mod processor;
+mod projection;
+mod record;
+mod render;
+mod scala;
+mod search;
 mod theory;
 mod data;
+mod decisions;
 mod tonal_space;
+mod tui;
 
-use lazy_static::lazy_static;
-
-use std::io::{stdin, stdout, Write};
+use std::io::stdin;
 use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
-use midir::{MidiInput, MidiOutput, MidiIO, Ignore};
-use std::sync::mpsc::channel;
+use clap::{Args, Parser, Subcommand};
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection, MidiIO, Ignore};
+use midly::number::u7;
+use regex::{Regex, RegexBuilder};
+use std::env;
+use std::fs;
+use std::sync::mpsc::{channel, Receiver};
 use std::thread;
-use crate::theory::Note;
+use std::time::{Duration, Instant};
+
+use crate::corpus::{ConfusionMatrix, Corpus};
+use crate::output::{AllocationPolicy, Discard, KeyPolicy, MidiSink, OutputConfig, OutputMode};
+use crate::decisions::DecisionLog;
+use crate::preset::Presets;
+use crate::processor::{Incoming, Processor, SpellingMode};
+use crate::record::{Recorder, Recording};
+use crate::search::{Piece, Setting};
+use crate::theory::{FifthsChain, Pitch31};
+use crate::tonal_space::{AssonanceMetric, ProjectionType, TonalSpaceConfig};
+use crate::tui::{Tui, View};
+
+// Exit codes, so that scripts launching the retuner unattended can tell what went wrong.
+// Command line parsing errors exit with 2 as well.
+const EXIT_ERROR: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_PORT_NOT_FOUND: u8 = 3;
+
+/// Retunes 12 edo MIDI to 31 edo, spelling each note according to its harmonic context
+#[derive(Parser)]
+// Later options override earlier ones, so that replays can override the settings of the recording
+#[command(name = "thirty_one_from_twelve", args_override_self = true)]
+struct Cli {
+    #[command(flatten)]
+    tonal: TonalArgs,
+
+    #[command(flatten)]
+    midi: MidiArgs,
+
+    #[command(subcommand)]
+    command: Command
+}
+
+#[derive(Args)]
+struct TonalArgs {
+    /// TOML file of tonal space parameters
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Sets a single tonal space parameter (applied after --config), e.g. --set semitone_threshold=2
+    #[arg(long = "set", value_name = "NAME=VALUE", global = true)]
+    overrides: Vec<String>,
+
+    /// TOML file of additional projection tables
+    #[arg(long, global = true)]
+    projections: Option<PathBuf>,
+
+    /// Projection type used to spell notes
+    #[arg(long, global = true, default_value = "Meantone31KeepUnison")]
+    projection: String,
+
+    /// Directory of tonal space presets, which live mode saves and recalls with "save NAME" and
+    /// "recall NAME" on the terminal, or recalls with CC 119 (presets numbered alphabetically from 0)
+    #[arg(long, value_name = "DIR", global = true)]
+    presets: Option<PathBuf>,
+
+    /// Spells every pitch class the same way, from a chain of 12 fifths given by its first and last
+    /// note (e.g. Eb:G#), instead of adapting to the harmonic context
+    #[arg(long, value_name = "FIRST:LAST", global = true)]
+    fifths: Option<String>
+}
+
+#[derive(Args)]
+struct MidiArgs {
+    /// Sends MPE: a lower zone with this many member channels (default 15) and a manager channel
+    #[arg(long, value_name = "MEMBERS", num_args = 0..=1, default_missing_value = "15", global = true)]
+    mpe: Option<u8>,
+
+    /// Pitch bend range of the synth in semitones [default: 2, or 48 with --mpe]
+    #[arg(long, value_name = "SEMITONES", global = true)]
+    bend_range: Option<f64>,
+
+    /// Which key 31 edo pitches are sent on: "nearest" (smallest bend) or "pressed" (the key that was played)
+    #[arg(long, value_name = "POLICY", global = true, default_value = "nearest")]
+    key_policy: String,
+
+    /// Sets the pitch bend range of the synth (RPN 0) on every voice channel before retuning.
+    /// Always done with --mpe
+    #[arg(long, global = true)]
+    send_rpn: bool,
+
+    /// How free channels are picked: "round-robin", "lru" (least recently used) or "same-bend"
+    /// (a channel already carrying the needed bend)
+    #[arg(long, value_name = "POLICY", global = true, default_value = "round-robin")]
+    allocation: String,
+
+    /// Ends the oldest note when all channels are in use, rather than sharing a channel whose bend fits the note
+    #[arg(long, global = true)]
+    steal_oldest: bool,
+
+    /// Reserves a channel (1-16) for controllers, program changes etc., which are then sent only there
+    /// rather than to every channel. For synths with a "global" or "main" channel setting
+    #[arg(long, value_name = "CHANNEL", global = true)]
+    global_channel: Option<u8>,
+
+    /// Frequency of A4 in Hz, or modern (440), verdi (432) or baroque (415).
+    /// MIDI output assumes the synth is tuned to 440 Hz, and is bent away from it
+    #[arg(long, value_name = "HZ", global = true, default_value = "440")]
+    reference: String,
+
+    /// Treats the inputs as MPE controllers (lower zone), keeping their per-note pitch bend, pressure and timbre
+    #[arg(long, global = true)]
+    mpe_in: bool
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Lists the available MIDI input and output ports
+    ListPorts,
+
+    /// Retunes live MIDI input
+    Live {
+        /// Input port, given as a case insensitive substring or regex of the port name.
+        /// Can be given several times, all these inputs share one tonal space (joint harmony)
+        #[arg(long = "in", value_name = "PATTERN")]
+        inputs: Vec<String>,
+
+        /// Like --in, but the input is spelled in its own tonal space
+        #[arg(long = "in-own", value_name = "PATTERN")]
+        own_inputs: Vec<String>,
+
+        /// Output port, given as a case insensitive substring or regex of the port name
+        #[arg(long = "out", value_name = "PATTERN")]
+        output: Option<String>,
+
+        /// Creates virtual ports called "<NAME> in" and "<NAME> out" for whichever of --in/--in-own
+        /// and --out is not given, so that other applications can connect to the retuner directly
+        #[arg(long = "virtual", value_name = "NAME", num_args = 0..=1, default_missing_value = "31from12")]
+        virtual_name: Option<String>,
+
+        /// Records every incoming message with its timestamp to a file, see the replay command
+        #[arg(long, value_name = "FILE")]
+        record: Option<PathBuf>,
+
+        /// Shows the tonal space, sounding notes and candidate scores in a full screen terminal view
+        /// instead of printing every message
+        #[arg(long)]
+        tui: bool,
+
+        /// Writes how every note on was spelled as a line of JSON to a file, or to stdout if "-"
+        #[arg(long, value_name = "FILE")]
+        log: Option<PathBuf>
+    },
+
+    /// Replays a recording made with live --record through the retuner, printing the spellings.
+    /// The recorded tonal space and MIDI options are used, unless they are given again
+    /// (recorded --set parameters are left out when --config is given)
+    Replay {
+        recording: PathBuf,
+
+        /// Output port to also send the retuned messages to
+        #[arg(long = "out", value_name = "PATTERN")]
+        output: Option<String>,
+
+        /// Replays as fast as possible instead of with the recorded timing
+        #[arg(long)]
+        fast: bool,
+
+        /// Compares the spellings with those printed by an earlier replay, and fails if they differ
+        #[arg(long, value_name = "FILE")]
+        diff: Option<PathBuf>,
+
+        /// Writes how every note on was spelled as a line of JSON to a file, or to stdout if "-"
+        #[arg(long, value_name = "FILE")]
+        log: Option<PathBuf>
+    },
+
+    /// Retunes a standard MIDI file
+    Convert {
+        input: PathBuf,
+        output: PathBuf
+    },
+
+    /// Renders a standard MIDI file (.mid, .midi) or a recording made with live --record to a WAV file,
+    /// with a built-in synth tuned to the chosen spellings
+    Render {
+        input: PathBuf,
+        output: PathBuf,
+
+        /// Plays a MIDI file written by convert back from its keys and pitch bends (with the --bend-range
+        /// or --mpe it was converted with) instead of spelling it again
+        #[arg(long)]
+        converted: bool
+    },
+
+    /// Spells golden corpus files (see tests/corpus) and reports the mismatches and accuracy of each.
+    /// Fails if a corpus is below its minimum accuracy, or has mistakes other than its known ones
+    Corpus {
+        #[arg(required = true)]
+        files: Vec<PathBuf>
+    },
+
+    /// Spells the notes of MusicXML scores and compares them with the written spelling,
+    /// printing the accuracy of each piece and a confusion matrix of all of them
+    Evaluate {
+        #[arg(required = true)]
+        files: Vec<PathBuf>
+    },
+
+    /// Searches for the tonal space parameters, metric and projection type that spell a set of pieces
+    /// (golden corpus .txt files or MusicXML scores) best, and prints the best configuration. The
+    /// setting given with --config, --set and --projection is tried first, and kept unless one spells better.
+    Search {
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+
+        /// Tries this many random settings instead of the full grid
+        #[arg(long, value_name = "COUNT")]
+        random: Option<usize>,
+
+        /// Seed of the random search
+        #[arg(long, default_value_t = 1)]
+        seed: u64,
+
+        /// Number of best settings to list
+        #[arg(long, default_value_t = 5)]
+        top: usize
+    },
+
+    /// Writes the 31 edo tuning as Scala files (<OUTPUT>.scl and <OUTPUT>.kbm), mapping the 12 keys
+    /// to the spelling they would get after playing the context keys, for synths that only take static tunings
+    ExportScala {
+        output: PathBuf,
+
+        /// MIDI keys played first, to give the tonal space a harmonic context
+        #[arg(value_parser = clap::value_parser!(u8).range(0..=127))]
+        context: Vec<u8>,
+
+        /// Maps each key to the spelling preferred by the projection table, ignoring harmonic context
+        #[arg(long = "static", conflicts_with_all = ["context", "fifths"])]
+        static_mapping: bool
+    },
+
+    /// Spells a sequence of MIDI keys (e.g. 60 64 67), shows how note names (e.g. Cb5) are tuned,
+    /// or finds the 31 edo pitch nearest to a frequency (e.g. 261.6Hz)
+    Spell {
+        #[arg(required = true)]
+        notes: Vec<String>
+    }
+}
+
+/// Invalid configuration given on the command line
+#[derive(Debug)]
+struct UsageError(String);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for UsageError {}
+
+/// No MIDI port matched the pattern given on the command line
+#[derive(Debug)]
+struct PortNotFound(String);
+
+impl fmt::Display for PortNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for PortNotFound {}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match run(cli) {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            if err.is::<UsageError>() {
+                ExitCode::from(EXIT_USAGE)
+            } else if err.is::<PortNotFound>() {
+                ExitCode::from(EXIT_PORT_NOT_FOUND)
+            } else {
+                ExitCode::from(EXIT_ERROR)
+            }
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    match cli.command {
+        Command::ListPorts => list_ports(),
+        Command::Live {inputs, own_inputs, output, virtual_name, record, tui, log} => {
+            let processor = make_processor(&cli.tonal, &cli.midi)?;
+            let settings = settings(processor.config(), &cli.tonal, &cli.midi);
+            if tui && log.as_deref() == Some(Path::new("-")) {
+                return Err(UsageError("--log - cannot be used with --tui, which uses the terminal".to_owned()).into());
+            }
+            let mut ins = inputs.into_iter().map(|p| (PortChoice::Existing(p), false))
+                .chain(own_inputs.into_iter().map(|p| (PortChoice::Existing(p), true)))
+                .collect::<Vec<_>>();
+            if ins.is_empty() {
+                ins.push((port_choice(None, &virtual_name, "in")?, false));
+            }
+            let output = port_choice(output, &virtual_name, "out")?;
+            live(ins, output, processor, cli.midi.mpe_in, record.map(|file| (file, settings)), tui, log)
+        }
+        Command::Convert {input, output} => {
+            let mut processor = make_processor(&cli.tonal, &cli.midi)?;
+            let input_idx = processor.add_input(false, cli.midi.mpe_in);
+            midi_file::convert(&input, &output, &mut processor, input_idx)?;
+            println!("Wrote {}", output.display());
+            Ok(())
+        }
+        Command::Replay {recording, output, fast, diff, log} => {
+            let recording = record::load(&recording)?;
+            let (tonal, midi) = with_recorded_settings(&recording, cli.tonal.config.is_some())?;
+            replay(recording, output, fast, diff, log, make_processor(&tonal, &midi)?, midi.mpe_in)
+        }
+        Command::Render {input, output, converted} => {
+            let is_midi_file = input.extension().is_some_and(|ext| ext == "mid" || ext == "midi");
+            if converted && !is_midi_file {
+                return Err(UsageError("--converted only applies to MIDI files (.mid, .midi)".to_owned()).into());
+            }
+            let (recording, tonal, midi) = if is_midi_file {
+                (None, cli.tonal, cli.midi)
+            } else {
+                let recording = record::load(&input)?;
+                let (tonal, midi) = with_recorded_settings(&recording, cli.tonal.config.is_some())?;
+                (Some(recording), tonal, midi)
+            };
+            render_wav(&input, recording, converted, &output, make_processor(&tonal, &midi)?, midi.mpe_in)
+        }
+        Command::Corpus {files} => run_corpus(&files, &cli.tonal),
+        Command::Evaluate {files} => evaluate(&files, &cli.tonal, &cli.midi),
+        Command::Search {files, random, seed, top} => {
+            let base = Setting {
+                config: make_config(&cli.tonal)?,
+                metric: AssonanceMetric::Pythagorean,
+                projection_type: projection_type(&cli.tonal)?
+            };
+            run_search(&files, base, random, seed, top)
+        }
+        Command::ExportScala {output, context, static_mapping} =>
+            export_scala(&output, &context, static_mapping, make_processor(&cli.tonal, &cli.midi)?),
+        Command::Spell {notes} => spell(&notes, make_processor(&cli.tonal, &cli.midi)?)
+    }
+}
+
+/// Reads the tonal space parameters and loads the projection tables given on the command line
+fn make_config(args: &TonalArgs) -> Result<TonalSpaceConfig, UsageError> {
+    let mut config = match &args.config {
+        Some(file) => TonalSpaceConfig::from_file(file).map_err(UsageError)?,
+        None => TonalSpaceConfig::default()
+    };
+
+    for o in &args.overrides {
+        let mut split = o.splitn(2, '=');
+        match (split.next(), split.next()) {
+            (Some(name), Some(value)) => config.set(name.trim(), value.trim()).map_err(UsageError)?,
+            _ => return Err(UsageError(format!("expected <name>=<value> after --set, found {}", o)))
+        }
+    }
+
+    if let Some(file) = &args.projections {
+        projection::load_tables(file).map_err(UsageError)?;
+    }
+    Ok(config)
+}
+
+fn make_processor(args: &TonalArgs, midi: &MidiArgs) -> Result<Processor, UsageError> {
+    processor_with(make_config(args)?, args, midi)
+}
+
+/// The options of `tonal` and `midi` that affect spelling and retuning, in the form they are recorded in
+/// (see `Recording::settings`). The tonal space parameters are written out with --set, so that
+/// replays do not depend on the config file.
+fn settings(config: TonalSpaceConfig, tonal: &TonalArgs, midi: &MidiArgs) -> Vec<String> {
+    let path = |p: &PathBuf| p.canonicalize().unwrap_or_else(|_| p.clone()).display().to_string();
+    let mut settings = config.to_toml().lines()
+        .map(|line| format!("--set {}", line.replace(" = ", "=")))
+        .collect::<Vec<_>>();
+
+    let options = vec![
+        ("--projections", tonal.projections.as_ref().map(path)),
+        ("--projection", Some(tonal.projection.clone())),
+        ("--presets", tonal.presets.as_ref().map(path)),
+        ("--fifths", tonal.fifths.clone()),
+        ("--mpe", midi.mpe.map(|m| m.to_string())),
+        ("--bend-range", midi.bend_range.map(|r| r.to_string())),
+        ("--key-policy", Some(midi.key_policy.clone())),
+        ("--allocation", Some(midi.allocation.clone())),
+        ("--global-channel", midi.global_channel.map(|c| c.to_string())),
+        ("--reference", Some(midi.reference.clone()))
+    ];
+    settings.extend(options.into_iter().filter_map(|(option, value)| value.map(|v| format!("{} {}", option, v))));
+    let flags = vec![("--send-rpn", midi.send_rpn), ("--steal-oldest", midi.steal_oldest), ("--mpe-in", midi.mpe_in)];
+    settings.extend(flags.into_iter().filter(|(_, on)| *on).map(|(flag, _)| flag.to_owned()));
+    settings
+}
+
+/// Parses the command line again with the settings of `recording` in front, so that the options
+/// given on the command line override them. Recorded --set parameters are left out if `has_config`,
+/// as they would override the config file.
+fn with_recorded_settings(recording: &Recording, has_config: bool) -> Result<(TonalArgs, MidiArgs), UsageError> {
+    let mut args = env::args_os().collect::<Vec<_>>();
+    let recorded = recording.settings.iter()
+        .filter(|s| !(has_config && s.starts_with("--set ")))
+        .flat_map(|s| match s.split_once(' ') {
+            Some((option, value)) => vec![option.into(), value.into()],
+            None => vec![s.into()]
+        });
+    args.splice(1..1, recorded);
+    let cli = Cli::try_parse_from(args).map_err(|e| {
+        let message = e.to_string();
+        let first_line = message.lines().next().unwrap_or("").trim_start_matches("error: ");
+        UsageError(format!("invalid recorded settings: {}", first_line))
+    })?;
+    Ok((cli.tonal, cli.midi))
+}
+
+/// Like `make_processor`, for a config made with `make_config`, which loads the projection tables only once
+/// The projection type given on the command line. Custom ones have to be loaded first, see `make_config`.
+fn projection_type(args: &TonalArgs) -> Result<ProjectionType, UsageError> {
+    ProjectionType::from_name(&args.projection)
+        .ok_or_else(|| UsageError(format!("unknown projection type: {}", args.projection)))
+}
+
+fn processor_with(config: TonalSpaceConfig, args: &TonalArgs, midi: &MidiArgs) -> Result<Processor, UsageError> {
+    let projection_type = projection_type(args)?;
+
+    let mode = match midi.mpe {
+        Some(members) => OutputMode::mpe(members).map_err(UsageError)?,
+        None => OutputMode::PerNoteChannel
+    };
+    let mut output = OutputConfig::new(mode);
+    // The bend range goes first, as the key policy and reference check that it is wide enough for them
+    if let Some(range) = midi.bend_range {
+        output.set_bend_range(range).map_err(UsageError)?;
+    }
+    let key_policy = KeyPolicy::from_name(&midi.key_policy)
+        .ok_or_else(|| UsageError(format!("unknown key policy: {}", midi.key_policy)))?;
+    output.set_key_policy(key_policy).map_err(UsageError)?;
+    output.set_reference(theory::reference_from_name(&midi.reference).map_err(UsageError)?).map_err(UsageError)?;
+    output.send_rpn = midi.send_rpn;
+    output.allocation = AllocationPolicy::from_name(&midi.allocation)
+        .ok_or_else(|| UsageError(format!("unknown allocation policy: {}", midi.allocation)))?;
+    output.steal_oldest = midi.steal_oldest;
+    if let Some(channel) = midi.global_channel {
+        if channel == 0 {
+            return Err(UsageError("MIDI channels are numbered 1 to 16".to_owned()));
+        }
+        output.set_global_channel(channel - 1).map_err(UsageError)?;
+    }
 
-fn main() -> Result<(), Box<dyn Error>> {
+    let mut processor = Processor::new(config, projection_type, AssonanceMetric::Pythagorean, output);
+    if let Some(dir) = &args.presets {
+        processor.set_presets(Presets::open(dir).map_err(UsageError)?);
+    }
+    if let Some(chain) = &args.fifths {
+        processor.set_spelling(SpellingMode::Static(FifthsChain::new(chain).map_err(UsageError)?));
+    }
+    Ok(processor)
+}
 
-    println!("{}", Note::Cx.fifths_to(Note::C));
+fn list_ports() -> Result<(), Box<dyn Error>> {
+    let midi_in = MidiInput::new("31 from 12 input")?;
+    let midi_out = MidiOutput::new("31 from 12 output")?;
 
-    // match run() {
-    //     Ok(_) => (),
-    //     Err(err) => println!("Error: {}", err)
-    // }
+    println!("Input ports:");
+    for p in midi_in.ports() {
+        println!("  {}", midi_in.port_name(&p)?);
+    }
+    println!("Output ports:");
+    for p in midi_out.ports() {
+        println!("  {}", midi_out.port_name(&p)?);
+    }
     Ok(())
 }
 
-fn run() -> Result<(), Box<dyn Error>> {
-    let mut midi_in = MidiInput::new("midir forwarding input")?;
-    midi_in.ignore(Ignore::None);
-    let midi_out = MidiOutput::new("midir forwarding output")?;
+/// How to open one side of the live connection
+enum PortChoice {
+    /// Connect to the existing port matching the pattern
+    Existing(String),
+    /// Create a virtual port with this name
+    Virtual(String)
+}
 
-    let in_port = select_port(&midi_in, "input")?;
-    println!();
-    let out_port = select_port(&midi_out, "output")?;
+fn port_choice(pattern: Option<String>, virtual_name: &Option<String>, side: &str) -> Result<PortChoice, UsageError> {
+    match (pattern, virtual_name) {
+        (Some(pattern), _) => Ok(PortChoice::Existing(pattern)),
+        (None, Some(name)) => Ok(PortChoice::Virtual(format!("{} {}", name, side))),
+        (None, None) => Err(UsageError(format!("either --{} or --virtual must be given", side)))
+    }
+}
+
+/// Retunes `inputs` to `output`. Each input is given with whether it is spelled in its own tonal space.
+/// Incoming messages are recorded to a file, with the command line options in `settings`, if `record`
+/// is given. The spellings are printed, unless the decisions are logged to stdout, which then only gets JSON.
+fn live(inputs: Vec<(PortChoice, bool)>, output: PortChoice, mut processor: Processor, mpe_in: bool,
+        record: Option<(PathBuf, Vec<String>)>, tui: bool, log: Option<PathBuf>) -> Result<(), Box<dyn Error>> {
+    let quiet = log.as_deref() == Some(Path::new("-"));
+    // Status lines go to the log of the terminal UI while it is running
+    let status = |line: &str| if quiet || tui { tui::report(line.to_owned()) } else { println!("{}", line) };
+    status("Opening connections");
 
-    println!("\nOpening connections");
-    let in_port_name = midi_in.port_name(&in_port)?;
-    let out_port_name = midi_out.port_name(&out_port)?;
+    let (mut conn_out, out_port_name) = open_output(output)?;
+    processor.start(&mut conn_out);
 
-    let mut conn_out = midi_out.connect(&out_port, "midir-forward")?;
+    let own_spaces = inputs.iter().map(|(_, own)| *own).collect::<Vec<_>>();
+    let recorder = match &record {
+        Some((file, settings)) => Some(Recorder::create(file, &own_spaces, settings)?),
+        None => None
+    };
+    let decisions = log.as_deref().map(DecisionLog::create).transpose()?;
 
     let (tx, rx) = channel();
-    let tx1 = tx.clone();
 
-    // _conn_in needs to be a named parameter, because it needs to be kept alive until the end of the scope
-    let _conn_in = midi_in.connect(&in_port, "midir-forward", move |stamp, mut message, _| {
+    // Connections need to be kept alive until the end of the scope
+    let mut conns_in = vec![];
+    let mut in_port_names = vec![];
+    // midir timestamps of different connections do not share a time base, so messages are stamped
+    // with a common clock instead
+    let start = Instant::now();
 
-        tx1.send(Some(message.to_vec()));
+    for (input, own_space) in inputs {
+        let mut midi_in = MidiInput::new("31 from 12 input")?;
+        midi_in.ignore(Ignore::None);
 
-        conn_out.send(message).unwrap_or_else(|_| println!("Error when forwarding message ..."));
-        println!("{}: {:?} (len = {})", stamp, message, message.len());
-    }, ())?;
+        let input_idx = processor.add_input(own_space, mpe_in);
+        let tx1 = tx.clone();
+        let callback = move |_, message: &[u8], _: &mut ()| {
+            let stamp = start.elapsed().as_micros() as u64;
+            tx1.send(Some(Incoming::Midi(input_idx, stamp, message.to_vec())))
+                .unwrap_or_else(|_| tui::report("Error when processing message ...".to_owned()));
+        };
 
-    thread::spawn(move|| {
-        processor::process(rx);
+        let (conn_in, in_port_name) = match input {
+            PortChoice::Existing(pattern) => {
+                let in_port = find_port(&midi_in, &pattern, "input")?;
+                let in_port_name = midi_in.port_name(&in_port)?;
+                (midi_in.connect(&in_port, "31from12-in", callback, ())?, in_port_name)
+            }
+            PortChoice::Virtual(name) => (create_virtual_input(midi_in, &name, callback)?, name)
+        };
+
+        let space = if own_space { "own tonal space" } else { "joint tonal space" };
+        in_port_names.push(format!("{}: '{}' ({})", input_idx, in_port_name, space));
+        conns_in.push(conn_in);
+    }
+
+    // Started before the status line below, so that it ends up in its log
+    let tui = if tui { Some(Tui::start()) } else { None };
+    let processing = thread::spawn(move|| {
+        if let Some(mut tui) = tui {
+            tui.draw(&View::of(&processor, 0));
+            processor::process(rx, processor, conn_out, recorder, decisions, |processor, input, pitch| {
+                if let Some(pitch) = pitch {
+                    tui.log(format!("{} -> {}", input, pitch));
+                }
+                tui.draw(&View::of(processor, input));
+            });
+        } else {
+            processor::process(rx, processor, conn_out, recorder, decisions, |_, input, pitch| {
+                if let Some(pitch) = pitch.filter(|_| !quiet) {
+                    println!("{} -> {}", input, pitch);
+                }
+            });
+        }
     });
 
-    println!("Connections open, forwarding from '{}' to '{}' (press enter to exit) ...", in_port_name, out_port_name);
+    status(&format!("Connections open, retuning from {} to '{}' (press enter to exit) ...",
+                    in_port_names.join(", "), out_port_name));
 
-    let mut input = String::new();
-    stdin().read_line(&mut input)?; // wait for next enter key press
+    // Terminal commands act on the tonal space of the first input
+    while let Some(line) = read_command()? {
+        match line.split_once(' ') {
+            Some(("save", name)) => tx.send(Some(Incoming::SavePreset(0, name.trim().to_owned())))?,
+            Some(("recall", name)) => tx.send(Some(Incoming::RecallPreset(0, name.trim().to_owned())))?,
+            _ => tui::report(format!("Unknown command '{}', expected 'save NAME', 'recall NAME' or an empty line \
+                                      to exit", line))
+        }
+    }
 
-    println!("Closing connections");
-    tx.send(None);
+    status("Closing connections");
+    tx.send(None)?;
+    processing.join().map_err(|_| "processing thread panicked")?;
     Ok(())
 }
 
-fn select_port<T: MidiIO>(midi_io: &T, descr: &str) -> Result<T::Port, Box<dyn Error>> {
-    println!("Available {} ports:", descr);
-    let midi_ports = midi_io.ports();
-    for (i, p) in midi_ports.iter().enumerate() {
-        println!("{}: {}", i, midi_io.port_name(p)?);
+/// Connects to the output port matching the pattern or creates a virtual one, returning the port name as well
+fn open_output(output: PortChoice) -> Result<(MidiOutputConnection, String), Box<dyn Error>> {
+    let midi_out = MidiOutput::new("31 from 12 output")?;
+    match output {
+        PortChoice::Existing(pattern) => {
+            let out_port = find_port(&midi_out, &pattern, "output")?;
+            let out_port_name = midi_out.port_name(&out_port)?;
+            Ok((midi_out.connect(&out_port, "31from12-out")?, out_port_name))
+        }
+        PortChoice::Virtual(name) => Ok((create_virtual_output(midi_out, &name)?, name))
+    }
+}
+
+/// Feeds a recording through `processor` with the recorded timing (unless `fast`), optionally sending
+/// the result to `output`. The spellings are printed (unless the decisions are logged to stdout),
+/// and compared with the spellings in `diff` if given.
+fn replay(recording: Recording, output: Option<String>, fast: bool, diff: Option<PathBuf>, log: Option<PathBuf>,
+          mut processor: Processor, mpe_in: bool) -> Result<(), Box<dyn Error>> {
+    for own_space in &recording.own_spaces {
+        processor.add_input(*own_space, mpe_in);
+    }
+    let expected = match &diff {
+        Some(file) => Some(fs::read_to_string(file).map_err(|e| format!("{}: {}", file.display(), e))?),
+        None => None
+    };
+    let quiet = log.as_deref() == Some(Path::new("-"));
+    let decisions = log.as_deref().map(DecisionLog::create).transpose()?;
+
+    let (tx, rx) = channel();
+    let processing = match output {
+        Some(pattern) => {
+            let (mut conn_out, _) = open_output(PortChoice::Existing(pattern))?;
+            processor.start(&mut conn_out);
+            spawn_replay(rx, processor, conn_out, decisions, quiet)
+        }
+        None => spawn_replay(rx, processor, Discard, decisions, quiet)
+    };
+
+    let start = Instant::now();
+    let first_stamp = recording.messages.first().map(|m| m.stamp).unwrap_or(0);
+    for m in recording.messages {
+        if !fast {
+            let due = Duration::from_micros(m.stamp.saturating_sub(first_stamp));
+            if let Some(wait) = due.checked_sub(start.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+        tx.send(Some(Incoming::Midi(m.input, m.stamp, m.message)))?;
+    }
+    tx.send(None)?;
+    let spellings = processing.join().map_err(|_| "processing thread panicked")?;
+
+    if let Some(expected) = expected {
+        // Only the spelling lines are compared, so the output of live can be used as well
+        let expected = expected.lines().map(str::trim).filter(|l| l.contains(" -> ")).collect::<Vec<_>>();
+        let mut differences = 0;
+        for i in 0..expected.len().max(spellings.len()) {
+            let (was, now) = (expected.get(i).copied(), spellings.get(i).map(String::as_str));
+            if was != now {
+                differences += 1;
+                eprintln!("spelling {}: expected {}, got {}", i + 1, was.unwrap_or("nothing"), now.unwrap_or("nothing"));
+            }
+        }
+        if differences > 0 {
+            return Err(format!("{} of {} spellings differ", differences, expected.len().max(spellings.len())).into());
+        }
+        eprintln!("All {} spellings match", spellings.len());
     }
-    print!("Please select {} port: ", descr);
-    stdout().flush()?;
+    Ok(())
+}
+
+/// Renders `recording`, or else the MIDI file `input`, to the WAV file `output`. The notes are spelled with
+/// `processor`, except for a MIDI file that was already `converted`, which is played back as it was retuned.
+fn render_wav(input: &Path, recording: Option<Recording>, converted: bool, output: &Path, mut processor: Processor,
+              mpe_in: bool) -> Result<(), Box<dyn Error>> {
+    let notes = if converted {
+        render::decode_notes(midi_file::timed_messages(input)?, processor.bend_range(), processor.reference())
+    } else if let Some(recording) = recording {
+        for own_space in &recording.own_spaces {
+            processor.add_input(*own_space, mpe_in);
+        }
+        render::spell_notes(&mut processor, render::recorded_messages(recording.messages))
+    } else {
+        let input_idx = processor.add_input(false, mpe_in);
+        let messages = midi_file::timed_messages(input)?.into_iter()
+            .map(|(time, raw)| (input_idx, time, raw))
+            .collect();
+        render::spell_notes(&mut processor, messages)
+    };
+
+    let samples = render::render(&notes, processor.reference());
+    render::write_wav(output, &samples)?;
+    println!("Wrote {} ({} notes, {:.1} s)", output.display(), notes.len(),
+             samples.len() as f64 / render::SAMPLE_RATE as f64);
+    Ok(())
+}
+
+/// Runs the processing of a replay on its own thread, like in live mode, returning the spellings,
+/// which are printed unless `quiet`
+fn spawn_replay(rx: Receiver<Option<Incoming>>, processor: Processor, out: impl MidiSink + Send + 'static,
+                decisions: Option<DecisionLog>, quiet: bool) -> thread::JoinHandle<Vec<String>> {
+    thread::spawn(move || {
+        let mut spellings = vec![];
+        processor::process(rx, processor, out, None, decisions, |_, input, pitch| {
+            if let Some(pitch) = pitch {
+                let line = format!("{} -> {}", input, pitch);
+                if !quiet {
+                    println!("{}", line);
+                }
+                spellings.push(line);
+            }
+        });
+        spellings
+    })
+}
+
+#[cfg(unix)]
+fn create_virtual_input<F>(midi_in: MidiInput, name: &str, callback: F) -> Result<MidiInputConnection<()>, Box<dyn Error>>
+    where F: FnMut(u64, &[u8], &mut ()) + Send + 'static {
+    use midir::os::unix::VirtualInput;
+    Ok(midi_in.create_virtual(name, callback, ())?)
+}
+
+#[cfg(not(unix))]
+fn create_virtual_input<F>(_: MidiInput, _: &str, _: F) -> Result<MidiInputConnection<()>, Box<dyn Error>>
+    where F: FnMut(u64, &[u8], &mut ()) + Send + 'static {
+    Err(UsageError("virtual ports are not supported on this platform".to_owned()).into())
+}
+
+#[cfg(unix)]
+fn create_virtual_output(midi_out: MidiOutput, name: &str) -> Result<MidiOutputConnection, Box<dyn Error>> {
+    use midir::os::unix::VirtualOutput;
+    Ok(midi_out.create_virtual(name)?)
+}
+
+#[cfg(not(unix))]
+fn create_virtual_output(_: MidiOutput, _: &str) -> Result<MidiOutputConnection, Box<dyn Error>> {
+    Err(UsageError("virtual ports are not supported on this platform".to_owned()).into())
+}
+
+/// Reads a command line from the terminal, returning None once an empty line is entered.
+/// If stdin is closed (e.g. when launched from a script), blocks until the process is killed instead.
+fn read_command() -> Result<Option<String>, Box<dyn Error>> {
     let mut input = String::new();
-    stdin().read_line(&mut input)?;
-    let port = midi_ports.get(input.trim().parse::<usize>()?)
-        .ok_or("Invalid port number")?;
-    Ok(port.clone())
-}
\ No newline at end of file
+    if stdin().read_line(&mut input)? == 0 {
+        // Without a terminal, run until killed
+        loop {
+            thread::park();
+        }
+    }
+    let command = input.trim();
+    Ok(if command.is_empty() { None } else { Some(command.to_owned()) })
+}
+
+/// Finds the first port whose name matches `pattern`, which is a case insensitive regex.
+/// Patterns that are not valid regexes are matched as plain substrings.
+fn find_port<T: MidiIO>(midi_io: &T, pattern: &str, descr: &str) -> Result<T::Port, Box<dyn Error>> {
+    let regex = RegexBuilder::new(pattern).case_insensitive(true).build()
+        .or_else(|_| RegexBuilder::new(&regex::escape(pattern)).case_insensitive(true).build())?;
+
+    let mut matching = vec![];
+    for p in midi_io.ports() {
+        let name = midi_io.port_name(&p)?;
+        if regex.is_match(&name) {
+            matching.push((p, name));
+        }
+    }
+
+    if matching.len() > 1 {
+        let names = matching.iter().map(|(_, n)| n.as_str()).collect::<Vec<_>>();
+        eprintln!("Several {} ports match '{}', using the first: {}", descr, pattern, names.join(", "));
+    }
+
+    matching.into_iter()
+        .next()
+        .map(|(p, _)| p)
+        .ok_or_else(|| PortNotFound(format!("no {} port matches '{}'", descr, pattern)).into())
+}
+
+fn spell(notes: &[String], mut processor: Processor) -> Result<(), Box<dyn Error>> {
+    let input = processor.add_input(false, false);
+
+    lazy_static::lazy_static! {
+        static ref MIDI_KEY: Regex = Regex::new(r"^\d+$").unwrap();
+        static ref FREQUENCY: Regex = RegexBuilder::new(r"^(\d+(\.\d*)?)hz$").case_insensitive(true).build().unwrap();
+    }
+
+    for n in notes {
+        if MIDI_KEY.is_match(n) {
+            let key = n.parse::<u8>().ok()
+                .and_then(u7::try_from)
+                .ok_or_else(|| UsageError(format!("invalid MIDI key: {}", n)))?;
+            println!("{} -> {}", n, processor.spell(input, key));
+        } else if let Some(capts) = FREQUENCY.captures(n) {
+            let hz = capts[1].parse::<f64>().ok()
+                .filter(|hz| *hz > 0.0)
+                .ok_or_else(|| UsageError(format!("invalid frequency: {}", n)))?;
+            let pitch = theory::nearest_pitch31(hz, processor.reference());
+            let cents = 1200.0 * (hz / pitch.frequency(processor.reference())).log2();
+            println!("{} -> {} {:+.1} cents", n, pitch, cents);
+        } else {
+            let pitch = Pitch31::new(n).map_err(UsageError)?;
+            let (key, cents) = output::key_and_cents(pitch, processor.reference());
+            println!("{}: {} steps from A4, {:.2} Hz, key {} {:+.1} cents", pitch, pitch.to_steps_from_a4(),
+                     pitch.frequency(processor.reference()), key, cents);
+        }
+    }
+    Ok(())
+}
+
+fn export_scala(output: &Path, context: &[u8], static_mapping: bool, mut processor: Processor)
+    -> Result<(), Box<dyn Error>> {
+    let mapping = if static_mapping {
+        processor.static_mapping()
+    } else {
+        let input = processor.add_input(false, false);
+        for key in context {
+            processor.spell(input, u7::from(*key));
+        }
+        processor.mapping(input)
+    };
+
+    let (scl, kbm) = scala::export(output, &mapping, processor.reference())?;
+    let names = mapping.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+    println!("Wrote {} and {} ({})", scl.display(), kbm.display(), names.join(" "));
+    Ok(())
+}
+
+/// Evaluates each corpus file with the tonal space parameters from the command line. The projection
+/// type and metric are given by each file.
+fn run_corpus(files: &[PathBuf], args: &TonalArgs) -> Result<(), Box<dyn Error>> {
+    let config = make_config(args)?;
+
+    let mut failed = vec![];
+    for file in files {
+        let corpus = Corpus::from_file(file)?;
+        let evaluation = corpus.evaluate(config);
+        for m in &evaluation.mismatches {
+            println!("{}:{}: key {}: expected {}, got {}{}", file.display(), m.line, m.key, m.expected, m.got,
+                     if m.known { " (known)" } else { "" });
+        }
+        println!("{}: {} of {} notes correct ({:.1}%)", file.display(),
+                 evaluation.total - evaluation.mismatches.len(), evaluation.total, evaluation.accuracy());
+        if evaluation.accuracy() < corpus.min_accuracy || evaluation.unexpected().next().is_some() {
+            failed.push(file.display().to_string());
+        }
+    }
+
+    if !failed.is_empty() {
+        return Err(format!("below minimum accuracy or with unexpected spellings: {}", failed.join(", ")).into());
+    }
+    Ok(())
+}
+
+/// Spells every MusicXML score in `files` with a fresh processor, comparing notes (ignoring the octave)
+/// with the written spelling
+fn evaluate(files: &[PathBuf], args: &TonalArgs, midi: &MidiArgs) -> Result<(), Box<dyn Error>> {
+    let mut confusion = ConfusionMatrix::default();
+    let (mut total, mut correct) = (0, 0);
+    let config = make_config(args)?;
+
+    for file in files {
+        let notes = musicxml::load(file)?;
+        let mut processor = processor_with(config, args, midi)?;
+        let input = processor.add_input(false, false);
+
+        let mut piece_correct = 0;
+        for note in &notes {
+            let chosen = processor.spell(input, note.key);
+            confusion.add(note.pitch.note, chosen.note);
+            if chosen.note == note.pitch.note {
+                piece_correct += 1;
+            }
+        }
+
+        let accuracy = if notes.is_empty() { 100.0 } else { piece_correct as f64 * 100.0 / notes.len() as f64 };
+        println!("{}: {} of {} notes correct ({:.1}%)", file.display(), piece_correct, notes.len(), accuracy);
+        total += notes.len();
+        correct += piece_correct;
+    }
+
+    if files.len() > 1 && total > 0 {
+        println!("Total: {} of {} notes correct ({:.1}%)", correct, total, correct as f64 * 100.0 / total as f64);
+    }
+    println!();
+    print!("{}", confusion);
+    Ok(())
+}
+
+/// Searches from the `base` setting given on the command line, which is tried first, so that it stays
+/// the best unless another setting spells more notes as written
+fn run_search(files: &[PathBuf], base: Setting, random: Option<usize>, seed: u64, top: usize)
+    -> Result<(), Box<dyn Error>> {
+    let pieces = files.iter().map(Piece::load).collect::<Result<Vec<_>, _>>()?;
+    let mut settings = vec![base];
+    settings.extend(match random {
+        Some(count) => search::random(count, seed),
+        None => search::grid()
+    });
+    eprintln!("Trying {} settings on {} pieces ...", settings.len(), pieces.len());
+    eprintln!("Given setting (--projection {}): {:.1}%", base.projection_type.name(), base.accuracy(&pieces));
+
+    let results = search::search(settings, &pieces);
+    for (setting, accuracy) in results.iter().take(top) {
+        let c = &setting.config;
+        println!("{:5.1}%  {} {}  semitone_threshold={} diff_oct_clash_threshold={} \
+                  order_precedence_coefficient={:.3} neutral_bias={:.1}",
+                 accuracy, setting.projection_type.name(), setting.metric.name(), c.semitone_threshold,
+                 c.diff_oct_clash_threshold, c.order_precedence_coefficient, c.neutral_bias);
+    }
+
+    if let Some((best, accuracy)) = results.first() {
+        println!();
+        println!("# Best setting ({:.1}%), use with --config and --projection {}", accuracy, best.projection_type.name());
+        print!("{}", best.config.to_toml());
+    }
+    Ok(())
+}
@@ -0,0 +1,106 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use midly::{Event, EventKind, Format, Header, MetaMessage, Smf, Timing};
+use midly::number::u28;
+
+use crate::processor::Processor;
+
+/// An event of the converted file, either produced by the processor or copied from the input
+enum Converted<'a> {
+    Raw(Vec<u8>),
+    Kind(EventKind<'a>)
+}
+
+/// Retunes a standard MIDI file by running every MIDI event through `processor` as `input`.
+///
+/// All tracks are merged into a single track. Meta events (tempo, time signature, ...) and
+/// SysEx are kept at their original position.
+pub fn convert<P: AsRef<Path>, Q: AsRef<Path>>(path: P, output: Q, processor: &mut Processor, input: usize)
+    -> Result<(), Box<dyn Error>> {
+
+    let data = fs::read(path)?;
+    let smf = Smf::parse(&data).map_err(|e| e.to_string())?;
+    let events = merged_events(&smf);
+
+    let mut setup: Vec<Vec<u8>> = vec![];
+    processor.start(&mut setup);
+    let mut converted = setup.into_iter().map(|m| (0, Converted::Raw(m))).collect::<Vec<_>>();
+    for (tick, kind) in events {
+        match kind {
+            EventKind::Midi {..} => {
+                let mut raw = vec![];
+                kind.write(&mut None, &mut raw)?;
+
+                let mut out: Vec<Vec<u8>> = vec![];
+                processor.handle(input, &raw, &mut out);
+                converted.extend(out.into_iter().map(|m| (tick, Converted::Raw(m))));
+            }
+            EventKind::Meta(MetaMessage::EndOfTrack) => (),
+            kind => converted.push((tick, Converted::Kind(kind)))
+        }
+    }
+
+    let mut track = vec![];
+    let mut last_tick = 0;
+    for (tick, ev) in &converted {
+        let kind = match ev {
+            Converted::Raw(raw) => EventKind::parse(&mut raw.as_slice(), &mut None).map_err(|e| e.to_string())?,
+            Converted::Kind(kind) => *kind
+        };
+        track.push(Event { delta: u28::from((tick - last_tick) as u32), kind });
+        last_tick = *tick;
+    }
+    track.push(Event { delta: u28::from(0), kind: EventKind::Meta(MetaMessage::EndOfTrack) });
+
+    let out = Smf::new(Header::new(Format::SingleTrack, smf.header.timing), vec![track])
+        .map_err(|e| e.to_string())?;
+    out.save(output)?;
+    Ok(())
+}
+
+/// Reads the MIDI channel messages of a standard MIDI file, with the time they are played at in seconds
+pub fn timed_messages<P: AsRef<Path>>(path: P) -> Result<Vec<(f64, Vec<u8>)>, String> {
+    let path = path.as_ref();
+    let data = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let smf = Smf::parse(&data).map_err(|e| e.to_string())?;
+
+    // Default tempo of 120 bpm until the first tempo event
+    let mut us_per_beat = 500_000.0;
+    let (mut last_tick, mut seconds) = (0, 0.0);
+    let mut messages = vec![];
+    for (tick, kind) in merged_events(&smf) {
+        let ticks = (tick - last_tick) as f64;
+        seconds += match smf.header.timing {
+            Timing::Metrical(tpb) => ticks * us_per_beat / 1e6 / tpb.as_int() as f64,
+            Timing::Timecode(fps, subframes) => ticks / fps.as_f32() as f64 / subframes as f64
+        };
+        last_tick = tick;
+
+        match kind {
+            EventKind::Meta(MetaMessage::Tempo(tempo)) => us_per_beat = tempo.as_int() as f64,
+            EventKind::Midi {..} => {
+                let mut raw = vec![];
+                kind.write(&mut None, &mut raw).map_err(|e| e.to_string())?;
+                messages.push((seconds, raw));
+            }
+            _ => ()
+        }
+    }
+    Ok(messages)
+}
+
+/// Merges all tracks by absolute time in ticks, keeping the order of simultaneous events
+fn merged_events<'a>(smf: &Smf<'a>) -> Vec<(u64, EventKind<'a>)> {
+    let mut events = vec![];
+    for (track_idx, track) in smf.tracks.iter().enumerate() {
+        let mut tick = 0u64;
+        for ev in track {
+            tick += ev.delta.as_int() as u64;
+            events.push((tick, track_idx, ev.kind));
+        }
+    }
+    events.sort_by_key(|(tick, track_idx, _)| (*tick, *track_idx));
+    events.into_iter().map(|(tick, _, kind)| (tick, kind)).collect()
+}
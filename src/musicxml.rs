@@ -0,0 +1,138 @@
+use std::convert::TryFrom;
+use std::fs;
+use std::path::Path;
+
+use midly::number::u7;
+use roxmltree::{Document, Node};
+
+use crate::theory::Pitch31;
+
+/// A note of a score, with the 12 edo key it is played on and its written spelling
+#[derive(Debug, PartialEq)]
+pub struct WrittenNote {
+    pub key: u7,
+
+    /// Written spelling, mapped into 31 edo by meantone (a sharp raises by 2 steps, a quarter tone sharp by 1)
+    pub pitch: Pitch31
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<WrittenNote>, String> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    parse(&source).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Returns the pitched notes of a partwise MusicXML score in the order they are played,
+/// with the notes of all parts merged. Rests, and notes continuing a tie, are left out.
+pub fn parse(source: &str) -> Result<Vec<WrittenNote>, String> {
+    let doc = Document::parse(source).map_err(|e| e.to_string())?;
+    let root = doc.root_element();
+    if !root.has_tag_name("score-partwise") {
+        return Err(format!("expected a partwise score, found <{}>", root.tag_name().name()));
+    }
+
+    // (onset in quarter notes, part, note)
+    let mut notes = vec![];
+
+    for (part_idx, part) in root.children().filter(|n| n.has_tag_name("part")).enumerate() {
+        let mut divisions = 1.0;
+        let mut time = 0.0;
+        let mut last_onset = 0.0;
+
+        for measure in part.children().filter(|n| n.has_tag_name("measure")) {
+            for el in measure.children().filter(Node::is_element) {
+                match el.tag_name().name() {
+                    "attributes" => {
+                        if let Some(d) = child_number(el, "divisions")? {
+                            divisions = d;
+                        }
+                    }
+                    "backup" => time -= child_number(el, "duration")?.unwrap_or(0.0) / divisions,
+                    "forward" => time += child_number(el, "duration")?.unwrap_or(0.0) / divisions,
+                    "note" => {
+                        // Chord notes start together with the previous note
+                        let onset = if has_child(el, "chord") { last_onset } else { time };
+                        if !has_child(el, "chord") {
+                            time += child_number(el, "duration")?.unwrap_or(0.0) / divisions;
+                        }
+                        last_onset = onset;
+
+                        let tie_stop = el.children().any(|t| t.has_tag_name("tie") && t.attribute("type") == Some("stop"));
+                        if let (Some(pitch), false) = (child(el, "pitch"), tie_stop) {
+                            notes.push((onset, part_idx, written_note(pitch)?));
+                        }
+                    }
+                    _ => ()
+                }
+            }
+        }
+    }
+
+    // Stable, so simultaneous notes of a part keep their written order
+    notes.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+    Ok(notes.into_iter().map(|(_, _, note)| note).collect())
+}
+
+fn written_note(pitch: Node) -> Result<WrittenNote, String> {
+    let step = child(pitch, "step").and_then(|s| s.text()).ok_or("<pitch> without <step>")?.trim();
+    let octave = child_number(pitch, "octave")?.ok_or("<pitch> without <octave>")? as i16;
+    let alter = child_number(pitch, "alter")?.unwrap_or(0.0);
+
+    let natural = Pitch31::new(&format!("{}{}", step, octave))?;
+    let semitone = match step {
+        "C" => 0, "D" => 2, "E" => 4, "F" => 5, "G" => 7, "A" => 9, "B" => 11,
+        _ => return Err(format!("invalid step: {}", step))
+    };
+    let key = 12 * (octave + 1) + semitone + alter.round() as i16;
+    let key = u8::try_from(key).ok()
+        .and_then(u7::try_from)
+        .ok_or_else(|| format!("{}{} is outside the MIDI key range", step, octave))?;
+
+    Ok(WrittenNote { key, pitch: natural + (alter * 2.0).round() as i16 })
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| n.has_tag_name(name))
+}
+
+fn has_child(node: Node, name: &str) -> bool {
+    child(node, name).is_some()
+}
+
+fn child_number(node: Node, name: &str) -> Result<Option<f64>, String> {
+    match child(node, name).and_then(|n| n.text()) {
+        Some(text) => text.trim().parse().map(Some).map_err(|_| format!("invalid <{}>: {}", name, text)),
+        None => Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_score() {
+        let score = r#"<?xml version="1.0"?>
+            <score-partwise>
+              <part id="P1"><measure number="1">
+                <attributes><divisions>2</divisions></attributes>
+                <note><pitch><step>E</step><alter>-1</alter><octave>4</octave></pitch><duration>4</duration><tie type="start"/></note>
+                <note><chord/><pitch><step>G</step><octave>4</octave></pitch><duration>4</duration></note>
+                <note><pitch><step>E</step><alter>-1</alter><octave>4</octave></pitch><duration>2</duration><tie type="stop"/></note>
+                <note><rest/><duration>2</duration></note>
+                <backup><duration>8</duration></backup>
+                <note><pitch><step>B</step><alter>1</alter><octave>2</octave></pitch><duration>2</duration></note>
+              </measure></part>
+              <part id="P2"><measure number="1">
+                <attributes><divisions>1</divisions></attributes>
+                <forward><duration>1</duration></forward>
+                <note><pitch><step>F</step><alter>2</alter><octave>3</octave></pitch><duration>1</duration></note>
+              </measure></part>
+            </score-partwise>"#;
+
+        let names = parse(score).unwrap().iter()
+            .map(|n| format!("{}:{}", n.key.as_int(), n.pitch))
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["63:Eb4", "67:G4", "48:B#2", "55:Fx3"]);
+    }
+}
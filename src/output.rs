@@ -0,0 +1,647 @@
+use midir::MidiOutputConnection;
+use midly::{EventKind, MidiMessage};
+use midly::number::u4;
+
+use crate::theory::{self, Pitch31};
+use crate::tui;
+
+/// Default pitch bend range of the receiving synth, in semitones
+pub const BEND_RANGE: f64 = 2.0;
+
+/// Pitch bend range of MPE member channels, in semitones, as recommended by the MPE specification
+pub const MPE_BEND_RANGE: f64 = 48.0;
+
+/// Manager channel of an MPE lower zone
+const MPE_MANAGER_CHANNEL: u8 = 0;
+
+/// Channel 10 is reserved for percussion in General MIDI, so notes are never allocated to it
+const PERCUSSION_CHANNEL: u8 = 9;
+
+/// Pitch bend value for no bend
+pub const BEND_CENTRE: u16 = 0x2000;
+
+/// Anything that retuned MIDI messages can be sent to
+pub trait MidiSink {
+    fn send(&mut self, message: &[u8]);
+}
+
+impl MidiSink for MidiOutputConnection {
+    fn send(&mut self, message: &[u8]) {
+        MidiOutputConnection::send(self, message)
+            .unwrap_or_else(|_| tui::report("Error when forwarding message ...".to_owned()));
+    }
+}
+
+impl MidiSink for Vec<Vec<u8>> {
+    fn send(&mut self, message: &[u8]) {
+        self.push(message.to_vec());
+    }
+}
+
+/// Sink that drops all messages, for when only the spellings are of interest
+pub struct Discard;
+
+impl MidiSink for Discard {
+    fn send(&mut self, _: &[u8]) {}
+}
+
+pub fn note_on(channel: u8, key: u8, vel: u8) -> [u8; 3] {
+    [0x90 | channel, key, vel]
+}
+
+pub fn note_off(channel: u8, key: u8, vel: u8) -> [u8; 3] {
+    [0x80 | channel, key, vel]
+}
+
+pub fn pitch_bend(channel: u8, bend: u16) -> [u8; 3] {
+    [0xE0 | channel, (bend & 0x7F) as u8, (bend >> 7) as u8]
+}
+
+/// Encodes `message` on `channel`
+pub fn on_channel(channel: u8, message: MidiMessage) -> Vec<u8> {
+    let mut raw = vec![];
+    EventKind::Midi { channel: u4::from(channel), message }
+        .write(&mut None, &mut raw)
+        .expect("writing to a Vec cannot fail");
+    raw
+}
+
+/// Sets registered parameter `param` of `channel` to `value` (data entry MSB) and `fine` (data entry LSB),
+/// then deselects it again so that stray data entry messages have no effect
+pub fn rpn(channel: u8, param: u8, value: u8, fine: u8) -> Vec<[u8; 3]> {
+    let cc = 0xB0 | channel;
+    vec![[cc, 101, 0], [cc, 100, param], [cc, 6, value], [cc, 38, fine], [cc, 101, 127], [cc, 100, 127]]
+}
+
+/// Converts a pitch bend wheel position into cents, for a wheel that spans `range` semitones
+pub fn bend_to_cents(bend: u16, range: f64) -> f64 {
+    (bend as f64 - BEND_CENTRE as f64) / BEND_CENTRE as f64 * range * 100.0
+}
+
+/// Converts an offset in cents into the 14 bit pitch bend for a synth with a bend range of `range` semitones
+pub fn cents_to_bend(cents: f64, range: f64) -> u16 {
+    let bend = BEND_CENTRE as f64 + cents / 100.0 / range * BEND_CENTRE as f64;
+    bend.round().clamp(0.0, 0x3FFF as f64) as u16
+}
+
+/// Returns the key of a 12 edo synth tuned to the standard reference that is nearest to `pitch`
+/// (with A4 at `reference` Hz), and how many cents `pitch` is above that key
+pub fn key_and_cents(pitch: Pitch31, reference: f64) -> (u8, f64) {
+    let cents = pitch.cents_from_a4() + theory::reference_cents(reference);
+    let key = (69.0 + cents / 100.0).round().clamp(0.0, 127.0) as u8;
+    (key, cents_above_key(pitch, key, reference))
+}
+
+fn cents_above_key(pitch: Pitch31, key: u8, reference: f64) -> f64 {
+    pitch.cents_offset_from_12edo(key) + theory::reference_cents(reference)
+}
+
+/// Which 12 edo key a 31 edo pitch is sent on, e.g. B#4 on C5 or on the B4 that was pressed
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum KeyPolicy {
+    /// The key nearest to the pitch, which keeps bends as small as possible
+    Nearest,
+
+    /// The key that was pressed, so that samplers play the sample mapped to that key
+    Pressed
+}
+
+impl KeyPolicy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "nearest" => Some(KeyPolicy::Nearest),
+            "pressed" => Some(KeyPolicy::Pressed),
+            _ => None
+        }
+    }
+
+    /// Returns the key to send `pitch` (with A4 at `reference` Hz) on when `pressed` was played,
+    /// and how many cents `pitch` is above that key. Pitches the pressed key cannot be bent to within
+    /// `bend_range` semitones are sent on the nearest key instead.
+    pub fn key_and_cents(self, pitch: Pitch31, pressed: u8, reference: f64, bend_range: f64) -> (u8, f64) {
+        match self {
+            KeyPolicy::Nearest => key_and_cents(pitch, reference),
+            KeyPolicy::Pressed => {
+                let cents = cents_above_key(pitch, pressed, reference);
+                if cents.abs() <= bend_range * 100.0 { (pressed, cents) } else { key_and_cents(pitch, reference) }
+            }
+        }
+    }
+
+    /// Bend range the policy needs with A4 at `reference` Hz, in semitones. The nearest key is never
+    /// more than half a semitone away. A semitone from the pressed key covers its usual spellings
+    /// (e.g. B# and Cb on B), but the spelling can drift further with the harmonic context, in which
+    /// case the nearest key is used, see `key_and_cents`.
+    fn max_bend(self, reference: f64) -> f64 {
+        match self {
+            KeyPolicy::Nearest => 0.5,
+            KeyPolicy::Pressed => 1.0 + theory::reference_cents(reference).abs() / 100.0
+        }
+    }
+}
+
+/// How retuned notes are laid out over the output channels
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OutputMode {
+    /// Every channel except percussion (and the global channel, if any) is used for notes
+    PerNoteChannel,
+
+    /// MPE lower zone: channel 1 is the manager channel, channels 2 to `members + 1` carry the notes
+    Mpe { members: u8 }
+}
+
+impl OutputMode {
+    /// Returns an MPE lower zone with `members` member channels
+    pub fn mpe(members: u8) -> Result<Self, String> {
+        if (1..=15).contains(&members) {
+            Ok(OutputMode::Mpe { members })
+        } else {
+            Err(format!("An MPE zone has between 1 and 15 member channels, not {}", members))
+        }
+    }
+
+    /// Default pitch bend range of the channels notes are sounding on, in semitones
+    pub fn default_bend_range(self) -> f64 {
+        match self {
+            OutputMode::PerNoteChannel => BEND_RANGE,
+            OutputMode::Mpe {..} => MPE_BEND_RANGE
+        }
+    }
+}
+
+/// How the channel allocator picks among the free channels
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AllocationPolicy {
+    /// The next free channel after the last one handed out
+    RoundRobin,
+
+    /// The free channel that has been idle the longest, giving release tails the most time to decay
+    /// before their channel is retuned
+    LeastRecentlyUsed,
+
+    /// A channel already carrying the bend the note needs, so that no retuning is heard at all.
+    /// Sounding channels of the same input are reused as well, except with MPE output, where that
+    /// would merge the per-note pressure and timbre of the notes. Otherwise like `LeastRecentlyUsed`.
+    SameBend
+}
+
+impl AllocationPolicy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "round-robin" => Some(AllocationPolicy::RoundRobin),
+            "lru" => Some(AllocationPolicy::LeastRecentlyUsed),
+            "same-bend" => Some(AllocationPolicy::SameBend),
+            _ => None
+        }
+    }
+}
+
+/// Everything about the receiving synth that retuned messages depend on
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OutputConfig {
+    pub mode: OutputMode,
+
+    /// Pitch bend range of the channels notes are sounding on, in semitones
+    bend_range: f64,
+
+    key_policy: KeyPolicy,
+
+    /// Frequency of A4 in Hz. Notes are bent away from the standard reference the synth is tuned to.
+    reference: f64,
+
+    /// Whether to set the bend range of the synth with RPN 0 before sending notes.
+    /// Always done in MPE mode, where it is part of the zone setup.
+    pub send_rpn: bool,
+
+    pub allocation: AllocationPolicy,
+
+    /// Whether to end the oldest note when all channels are in use, rather than sharing a channel
+    /// whose pitch bend fits the new note
+    pub steal_oldest: bool,
+
+    /// Channel reserved for channel wide messages, which the synth applies to all channels,
+    /// instead of mirroring them to every channel notes are sounding on
+    global_channel: Option<u8>
+}
+
+impl OutputConfig {
+    pub fn new(mode: OutputMode) -> Self {
+        OutputConfig {
+            mode,
+            bend_range: mode.default_bend_range(),
+            key_policy: KeyPolicy::Nearest,
+            reference: theory::STANDARD_REFERENCE,
+            send_rpn: false,
+            allocation: AllocationPolicy::RoundRobin,
+            steal_oldest: false,
+            global_channel: None
+        }
+    }
+
+    pub fn bend_range(&self) -> f64 {
+        self.bend_range
+    }
+
+    pub fn key_policy(&self) -> KeyPolicy {
+        self.key_policy
+    }
+
+    pub fn reference(&self) -> f64 {
+        self.reference
+    }
+
+    /// Sets the bend range to `semitones`, which must be representable by RPN 0 and wide enough
+    /// for the key policy, see `KeyPolicy::max_bend`
+    pub fn set_bend_range(&mut self, semitones: f64) -> Result<(), String> {
+        let min = self.key_policy.max_bend(self.reference);
+        if (min..128.0).contains(&semitones) {
+            self.bend_range = semitones;
+            Ok(())
+        } else {
+            Err(format!("bend range must be at least {:.2} and below 128 semitones with the {:?} key policy, not {}",
+                        min, self.key_policy, semitones))
+        }
+    }
+
+    /// Sets the key policy, which fails if the bend range is too small for it
+    pub fn set_key_policy(&mut self, key_policy: KeyPolicy) -> Result<(), String> {
+        if self.bend_range < key_policy.max_bend(self.reference) {
+            return Err(format!("the {:?} key policy needs a bend range of at least {:.2} semitones",
+                               key_policy, key_policy.max_bend(self.reference)));
+        }
+        self.key_policy = key_policy;
+        Ok(())
+    }
+
+    /// Sets the frequency of A4 in Hz, which fails if the bend range is too small to reach it
+    pub fn set_reference(&mut self, reference: f64) -> Result<(), String> {
+        let min = self.key_policy.max_bend(reference);
+        if self.bend_range < min {
+            return Err(format!("A4 at {} Hz needs a bend range of at least {:.2} semitones with the {:?} key policy",
+                               reference, min, self.key_policy));
+        }
+        self.reference = reference;
+        Ok(())
+    }
+
+    /// Reserves `channel` (0 based) for channel wide messages. MPE zones have their own manager channel.
+    pub fn set_global_channel(&mut self, channel: u8) -> Result<(), String> {
+        if let OutputMode::Mpe {..} = self.mode {
+            return Err("MPE output always uses channel 1 as its global (manager) channel".to_owned());
+        }
+        if channel > 15 {
+            return Err(format!("there is no MIDI channel {}", channel + 1));
+        }
+        self.global_channel = Some(channel);
+        Ok(())
+    }
+
+    /// Channel that channel wide messages are sent to on behalf of all notes, if any
+    pub fn global_channel(&self) -> Option<u8> {
+        match self.mode {
+            OutputMode::PerNoteChannel => self.global_channel,
+            OutputMode::Mpe {..} => Some(MPE_MANAGER_CHANNEL)
+        }
+    }
+
+    /// Channels notes can be allocated to
+    fn voice_channels(&self) -> Vec<u8> {
+        match self.mode {
+            OutputMode::PerNoteChannel => (0..16)
+                .filter(|c| *c != PERCUSSION_CHANNEL && Some(*c) != self.global_channel)
+                .collect(),
+            OutputMode::Mpe { members } => (1..=members).map(|i| MPE_MANAGER_CHANNEL + i).collect()
+        }
+    }
+
+    /// Messages that configure the receiving synth, to be sent before any notes
+    pub fn setup_messages(&self) -> Vec<[u8; 3]> {
+        let mut messages = vec![];
+        if let OutputMode::Mpe { members } = self.mode {
+            // MPE Configuration Message (RPN 6) on the manager channel
+            messages.extend(rpn(MPE_MANAGER_CHANNEL, 6, members, 0));
+        }
+        if self.send_rpn || self.mode != OutputMode::PerNoteChannel {
+            let semitones = self.bend_range.trunc();
+            let cents = ((self.bend_range - semitones) * 100.0).round().min(99.0);
+            for channel in self.voice_channels() {
+                messages.extend(rpn(channel, 0, semitones as u8, cents as u8));
+            }
+        }
+        messages
+    }
+}
+
+/// Hands out a separate output channel to each sounding note,
+/// so that every note can be retuned with its own pitch bend
+pub struct ChannelAllocator {
+    /// Channels notes can be allocated to, in the order they are handed out
+    channels: Vec<u8>,
+
+    policy: AllocationPolicy,
+    steal_oldest: bool,
+
+    /// Whether notes have their own expression on their channel (MPE), so that sharing a channel
+    /// is a last resort
+    mpe: bool,
+
+    /// Keys sounding on each channel, with the time they were allocated
+    sounding: [Vec<(u8, u64)>; 16],
+
+    /// Input whose notes are sounding on each channel
+    owner: [Option<usize>; 16],
+
+    /// Pitch bend last sent on each channel
+    bend: [Option<u16>; 16],
+
+    /// Time each channel was last allocated or released
+    last_used: [u64; 16],
+
+    /// Counts allocations and releases, to order them in time
+    clock: u64,
+
+    /// Index into `channels` of where to start looking for the next free channel
+    next: usize
+}
+
+impl ChannelAllocator {
+    pub fn new(config: &OutputConfig) -> Self {
+        ChannelAllocator {
+            channels: config.voice_channels(),
+            policy: config.allocation,
+            steal_oldest: config.steal_oldest,
+            mpe: matches!(config.mode, OutputMode::Mpe {..}),
+            sounding: Default::default(),
+            owner: [None; 16],
+            bend: [None; 16],
+            last_used: [0; 16],
+            clock: 0,
+            next: 0
+        }
+    }
+
+    /// Returns a channel for a note played on `input`, sent on `key` with pitch bend `bend`,
+    /// together with the keys on that channel whose notes the caller has to end first.
+    ///
+    /// Free channels are picked according to the allocation policy. If all channels are in use,
+    /// either the channel of the oldest note of the same input is stolen, or a channel of the same
+    /// input that already has pitch bend `bend` is shared, so that no sounding note is retuned.
+    /// Without such a channel, the oldest note is stolen anyway. If `input` has no channels at all,
+    /// there is no channel for the note and None is returned. Shared channels never get a second
+    /// note on `key` unless `key` sounds on every channel of the input with that bend, in which case
+    /// it is ended.
+    pub fn allocate(&mut self, input: usize, key: u8, bend: u16) -> Option<(u8, Vec<u8>)> {
+        let order = (0..self.channels.len())
+            .map(|i| self.channels[(self.next + i) % self.channels.len()])
+            .collect::<Vec<_>>();
+        let free = order.iter().copied()
+            .filter(|c| self.sounding[*c as usize].is_empty())
+            .collect::<Vec<_>>();
+        let least_recent = free.iter().copied().min_by_key(|c| self.last_used[*c as usize]);
+
+        let choice = match self.policy {
+            AllocationPolicy::RoundRobin => free.first().copied(),
+            AllocationPolicy::LeastRecentlyUsed => least_recent,
+            AllocationPolicy::SameBend => order.iter().copied()
+                .filter(|c| !self.has_key(*c, key) && self.bend[*c as usize] == Some(bend))
+                .find(|c| self.sounding[*c as usize].is_empty()
+                      || (!self.mpe && self.owner[*c as usize] == Some(input)))
+                .or(least_recent)
+        };
+
+        let own = order.iter().copied()
+            .filter(|c| self.owner[*c as usize] == Some(input))
+            .collect::<Vec<_>>();
+        let (channel, ended) = match choice {
+            Some(channel) => (channel, vec![]),
+            None if self.steal_oldest => self.oldest(&own)?,
+            None => {
+                // Sending the new bend on a shared channel would retune the notes sounding there
+                let same_bend = own.iter().copied()
+                    .filter(|c| self.bend[*c as usize] == Some(bend))
+                    .collect::<Vec<_>>();
+                let shared = same_bend.iter().copied()
+                    .find(|c| !self.has_key(*c, key))
+                    .or_else(|| same_bend.first().copied());
+                match shared {
+                    Some(channel) => (channel, if self.has_key(channel, key) { vec![key] } else { vec![] }),
+                    None => self.oldest(&own)?
+                }
+            }
+        };
+
+        self.sounding[channel as usize].push((key, self.clock));
+        self.owner[channel as usize] = Some(input);
+        self.bend[channel as usize] = Some(bend);
+        self.last_used[channel as usize] = self.clock;
+        self.clock += 1;
+        let idx = self.channels.iter().position(|c| *c == channel).unwrap_or(0);
+        self.next = (idx + 1) % self.channels.len();
+        Some((channel, ended))
+    }
+
+    /// The channel of the oldest note among `channels`, with all keys sounding on it
+    fn oldest(&self, channels: &[u8]) -> Option<(u8, Vec<u8>)> {
+        let channel = channels.iter().copied()
+            .min_by_key(|c| self.sounding[*c as usize].iter().map(|(_, t)| *t).min())?;
+        Some((channel, self.sounding[channel as usize].iter().map(|(k, _)| *k).collect()))
+    }
+
+    fn has_key(&self, channel: u8, key: u8) -> bool {
+        self.sounding[channel as usize].iter().any(|(k, _)| *k == key)
+    }
+
+    /// Records that the pitch bend of `channel` changed, e.g. because the pitch bend wheel moved
+    pub fn set_bend(&mut self, channel: u8, bend: u16) {
+        self.bend[channel as usize] = Some(bend);
+    }
+
+    /// All channels notes can be allocated to
+    pub fn channels(&self) -> &[u8] {
+        &self.channels
+    }
+
+    /// Releases the oldest note on `key` sounding on `channel`
+    pub fn release(&mut self, channel: u8, key: u8) {
+        let keys = &mut self.sounding[channel as usize];
+        if let Some(pos) = keys.iter().position(|(k, _)| *k == key) {
+            keys.remove(pos);
+            if keys.is_empty() {
+                self.owner[channel as usize] = None;
+            }
+            self.last_used[channel as usize] = self.clock;
+            self.clock += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inputs_do_not_share_channels() {
+        let mut alloc = ChannelAllocator::new(&OutputConfig::new(OutputMode::PerNoteChannel));
+        let first = (0..15).map(|k| alloc.allocate(0, 60 + k, BEND_CENTRE).unwrap().0).collect::<Vec<_>>();
+        assert!(!first.contains(&PERCUSSION_CHANNEL));
+
+        // Input 1 only gets a channel once input 0 releases one
+        assert_eq!(alloc.allocate(1, 50, BEND_CENTRE), None);
+        alloc.release(first[3], 63);
+        assert_eq!(alloc.allocate(1, 50, BEND_CENTRE).unwrap().0, first[3]);
+
+        // When full, input 1 shares its own channel rather than one of input 0's
+        assert_eq!(alloc.allocate(1, 51, BEND_CENTRE).unwrap().0, first[3]);
+    }
+
+    #[test]
+    fn mpe_setup() {
+        let mode = OutputMode::mpe(3).unwrap();
+        assert_eq!(ChannelAllocator::new(&OutputConfig::new(mode)).channels(), &[1, 2, 3]);
+
+        let setup = OutputConfig::new(mode).setup_messages();
+        assert_eq!(&setup[..4], &[[0xB0, 101, 0], [0xB0, 100, 6], [0xB0, 6, 3], [0xB0, 38, 0]]);
+        // Bend range of 48 semitones on every member channel
+        for channel in 1..=3 {
+            assert!(setup.contains(&[0xB0 | channel, 6, 48]));
+        }
+        assert!(OutputMode::mpe(16).is_err());
+    }
+
+    #[test]
+    fn bend_range_rpn() {
+        let mut config = OutputConfig::new(OutputMode::PerNoteChannel);
+        assert!(config.setup_messages().is_empty());
+
+        config.set_bend_range(2.5).unwrap();
+        config.send_rpn = true;
+        let setup = config.setup_messages();
+        assert_eq!(setup.len(), 15 * 6);
+        assert_eq!(&setup[..4], &[[0xB0, 101, 0], [0xB0, 100, 0], [0xB0, 6, 2], [0xB0, 38, 50]]);
+
+        assert!(config.set_bend_range(0.2).is_err());
+        assert_eq!(cents_to_bend(-50.0, 2.5), BEND_CENTRE - 1638);
+    }
+
+    #[test]
+    fn shared_channels_avoid_sounding_key() {
+        let mut alloc = ChannelAllocator::new(&OutputConfig::new(OutputMode::mpe(2).unwrap()));
+        let (a, _) = alloc.allocate(0, 60, BEND_CENTRE).unwrap();
+        let (b, _) = alloc.allocate(0, 62, BEND_CENTRE).unwrap();
+        // Both channels are full, so key 60 has to share the channel of key 62
+        assert_eq!(alloc.allocate(0, 60, BEND_CENTRE), Some((b, vec![])));
+        assert_eq!(alloc.allocate(0, 62, BEND_CENTRE), Some((a, vec![])));
+        // Key 60 now sounds everywhere, so the note already sounding there has to end
+        let (_, ended) = alloc.allocate(0, 60, BEND_CENTRE).unwrap();
+        assert_eq!(ended, vec![60]);
+    }
+
+    #[test]
+    fn shared_channels_keep_their_bend() {
+        let mut alloc = ChannelAllocator::new(&OutputConfig::new(OutputMode::mpe(2).unwrap()));
+        let (a, _) = alloc.allocate(0, 60, 0x2100).unwrap();
+        let (b, _) = alloc.allocate(0, 62, 0x1F00).unwrap();
+        // Key 67 is shared with key 62, which has its bend, and key 62 keeps sounding as it was
+        assert_eq!(alloc.allocate(0, 67, 0x1F00), Some((b, vec![])));
+        assert_eq!(alloc.bend[b as usize], Some(0x1F00));
+        // No channel has the bend of key 65, so the oldest note is ended rather than retuned
+        assert_eq!(alloc.allocate(0, 65, 0x2200), Some((a, vec![60])));
+        assert_eq!(alloc.bend[b as usize], Some(0x1F00));
+    }
+
+    #[test]
+    fn key_policies() {
+        let b_sharp = Pitch31::new("B#4").unwrap();
+        let (key, cents) = KeyPolicy::Nearest.key_and_cents(b_sharp, 71, 440.0, 2.0);
+        assert_eq!(key, 72);
+        assert!(cents < 0.0);
+        let (key, cents) = KeyPolicy::Pressed.key_and_cents(b_sharp, 71, 440.0, 2.0);
+        assert_eq!(key, 71);
+        assert!(cents > 50.0 && cents < 100.0);
+        // Dx4 played on B4 is beyond the bend range, and goes to the nearest key (E4)
+        let (key, cents) = KeyPolicy::Pressed.key_and_cents(Pitch31::new("Dx4").unwrap(), 71, 440.0, 2.0);
+        assert_eq!(key, 64);
+        assert!(cents.abs() < 50.0);
+
+        // A baroque A4 is about a semitone lower, so A4 moves to the key of Ab4
+        let (key, cents) = KeyPolicy::Nearest.key_and_cents(Pitch31::new("A4").unwrap(), 69, 415.0, 2.0);
+        assert_eq!(key, 68);
+        assert!((cents - -1.3).abs() < 0.1);
+
+        let mut config = OutputConfig::new(OutputMode::PerNoteChannel);
+        config.set_bend_range(0.5).unwrap();
+        assert!(config.set_key_policy(KeyPolicy::Pressed).is_err());
+        config.set_bend_range(1.0).unwrap();
+        config.set_key_policy(KeyPolicy::Pressed).unwrap();
+        assert!(config.set_bend_range(0.5).is_err());
+        assert!(config.set_reference(415.0).is_err());
+        config.set_bend_range(2.1).unwrap();
+        config.set_reference(415.0).unwrap();
+    }
+
+    fn config(policy: AllocationPolicy, steal_oldest: bool) -> OutputConfig {
+        let mut config = OutputConfig::new(OutputMode::mpe(3).unwrap());
+        config.allocation = policy;
+        config.steal_oldest = steal_oldest;
+        config
+    }
+
+    #[test]
+    fn least_recently_used() {
+        let mut alloc = ChannelAllocator::new(&config(AllocationPolicy::LeastRecentlyUsed, false));
+        let (a, _) = alloc.allocate(0, 60, BEND_CENTRE).unwrap();
+        let (b, _) = alloc.allocate(0, 62, BEND_CENTRE).unwrap();
+        alloc.release(b, 62);
+        alloc.release(a, 60);
+        // Channel 3 was never used, then channel b was released before a
+        assert_eq!(alloc.allocate(0, 64, BEND_CENTRE).unwrap().0, 3);
+        assert_eq!(alloc.allocate(0, 65, BEND_CENTRE).unwrap().0, b);
+    }
+
+    #[test]
+    fn same_bend() {
+        let mut per_note = config(AllocationPolicy::SameBend, false);
+        per_note.mode = OutputMode::PerNoteChannel;
+        let mut alloc = ChannelAllocator::new(&per_note);
+        let (a, _) = alloc.allocate(0, 60, 0x2100).unwrap();
+        alloc.allocate(0, 62, 0x1F00);
+        // A sounding channel of the same input with the same bend is shared
+        assert_eq!(alloc.allocate(0, 67, 0x2100).unwrap().0, a);
+        // But never for the same key
+        assert_ne!(alloc.allocate(0, 60, 0x2100).unwrap().0, a);
+
+        // With MPE, notes keep a channel of their own while there are free ones
+        let mut alloc = ChannelAllocator::new(&config(AllocationPolicy::SameBend, false));
+        let (a, _) = alloc.allocate(0, 60, 0x2100).unwrap();
+        assert_ne!(alloc.allocate(0, 67, 0x2100).unwrap().0, a);
+    }
+
+    #[test]
+    fn steal_oldest() {
+        let mut alloc = ChannelAllocator::new(&config(AllocationPolicy::RoundRobin, true));
+        let (a, _) = alloc.allocate(0, 60, BEND_CENTRE).unwrap();
+        alloc.allocate(0, 62, BEND_CENTRE);
+        alloc.allocate(0, 64, BEND_CENTRE);
+        assert_eq!(alloc.allocate(0, 65, BEND_CENTRE), Some((a, vec![60])));
+
+        // Only notes of the same input are stolen, and a released channel has no owner any more
+        let mut alloc = ChannelAllocator::new(&config(AllocationPolicy::RoundRobin, true));
+        let (a, _) = alloc.allocate(0, 60, BEND_CENTRE).unwrap();
+        let (b, _) = alloc.allocate(1, 62, BEND_CENTRE).unwrap();
+        alloc.allocate(1, 64, BEND_CENTRE);
+        assert_eq!(alloc.allocate(1, 65, BEND_CENTRE), Some((b, vec![62])));
+        alloc.release(a, 60);
+        assert_eq!(alloc.owner[a as usize], None);
+        assert_eq!(alloc.allocate(1, 67, BEND_CENTRE).unwrap().0, a);
+    }
+
+    #[test]
+    fn global_channel_is_reserved() {
+        let mut config = OutputConfig::new(OutputMode::PerNoteChannel);
+        config.set_global_channel(0).unwrap();
+        assert_eq!(config.global_channel(), Some(0));
+        assert_eq!(ChannelAllocator::new(&config).channels().len(), 14);
+        assert!(!ChannelAllocator::new(&config).channels().contains(&0));
+        assert!(OutputConfig::new(OutputMode::mpe(3).unwrap()).set_global_channel(0).is_err());
+    }
+}
@@ -0,0 +1,51 @@
+use std::fs;
+use std::path::PathBuf;
+
+use crate::tonal_space::{TonalSpace, TonalSpaceConfig};
+
+/// A directory of saved tonal space states, one `<name>.toml` file per preset (see `TonalSpace::to_toml`)
+pub struct Presets {
+    dir: PathBuf
+}
+
+impl Presets {
+    /// Uses the presets in `dir`, which is created if it does not exist yet
+    pub fn open<P: Into<PathBuf>>(dir: P) -> Result<Self, String> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        Ok(Presets { dir })
+    }
+
+    /// Names of the saved presets in alphabetical order, which is the order they are numbered in
+    /// for recalling them by MIDI controller
+    pub fn names(&self) -> Result<Vec<String>, String> {
+        let entries = fs::read_dir(&self.dir).map_err(|e| format!("{}: {}", self.dir.display(), e))?;
+        let mut names = entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+            .filter_map(|path| path.file_stem().map(|stem| stem.to_string_lossy().into_owned()))
+            .collect::<Vec<_>>();
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn save(&self, name: &str, tonal_space: &TonalSpace) -> Result<PathBuf, String> {
+        let path = self.path(name)?;
+        fs::write(&path, tonal_space.to_toml()).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(path)
+    }
+
+    /// Loads the preset `name`, to be used with `config`
+    pub fn load(&self, name: &str, config: TonalSpaceConfig) -> Result<TonalSpace, String> {
+        let path = self.path(name)?;
+        let source = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        TonalSpace::from_toml(&source, config).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, String> {
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            return Err(format!("invalid preset name: '{}'", name));
+        }
+        Ok(self.dir.join(format!("{}.toml", name)))
+    }
+}
@@ -1,61 +1,629 @@
+use std::path::PathBuf;
 use std::sync::mpsc::Receiver;
 use midly::{EventKind, MidiMessage};
 use std::collections::HashMap;
 use midly::number::u7;
 
-use crate::theory::{Note, Pitch31};
-use crate::data::{ActiveNote};
-use crate::tonal_space::{TSPitch, TonalSpace};
+use crate::theory::{FifthsChain, Pitch31};
+use crate::data::ActiveNote;
+use crate::decisions::{Decision, DecisionLog};
+use crate::output::{self, ChannelAllocator, MidiSink, OutputConfig};
+use crate::preset::Presets;
+use crate::record::Recorder;
+use crate::tonal_space::{TonalSpace, TonalSpaceConfig, AssonanceMetric, ProjectionType};
+use crate::tui;
 
+/// Holding down this controller (soft pedal by default) lets 12 edo seconds, thirds,
+/// sixths and sevenths be projected onto 31 edo neutral intervals.
+const NEUTRAL_MODIFIER_CC: u8 = 67;
 
-pub(crate) fn process(rx: Receiver<Option<Vec<u8>>>) {
+/// Per-note timbre controller of MPE (CC 74, brightness)
+const MPE_TIMBRE_CC: u8 = 74;
 
+/// Recalls the saved tonal space preset numbered by the controller value (in alphabetical order of
+/// the preset names) into the tonal space of the input. CC 119 is undefined in the MIDI specification.
+const PRESET_RECALL_CC: u8 = 119;
+
+/// Manager channel of the lower zone of an MPE input, which is the only zone supported for inputs
+const MPE_INPUT_MANAGER_CHANNEL: u8 = 0;
+
+/// Converts incoming 12 edo MIDI messages into retuned 31 edo MIDI messages
+pub struct Processor {
     // Keys contain collection of notes in tonal space,
     // and it maps to a list of notes across the different octaves it spans
     // TODO: Determine if the semitone interval for tonal space should
     // be octave-dependant or not. (E.g. whether C4 B4 would clear C4 from the tonal space
     // just as how C4 B3 does.
-    let mut tonal_space = TonalSpace::new();
+    //
+    // The first tonal space is shared by all inputs that play in joint harmony,
+    // the others each belong to a single input.
+    tonal_spaces: Vec<TonalSpace>,
 
-    let mut active_notes: HashMap<u7, ActiveNote> = HashMap::new();
+    config: TonalSpaceConfig,
+    projection_type: ProjectionType,
+    metric: AssonanceMetric,
+    spelling: SpellingMode,
 
-    let mut parser_running_status = None;
+    inputs: Vec<InputState>,
 
-    // Once None is sent, app will be terminated
-    while let Some(mut raw) = rx.recv().unwrap() {
-        let mut raw = raw.as_slice();
-        let parsed_msg = EventKind::parse(&mut raw, &mut parser_running_status);
-        let ev;
-        match parsed_msg {
-            Ok(event) => {
-                ev = event;
-            }
+    /// Sounding notes, keyed by input, input channel and key
+    active_notes: HashMap<(usize, u8, u8), ActiveNote>,
+
+    output: OutputConfig,
+    allocator: ChannelAllocator,
+
+    presets: Option<Presets>,
+
+    /// The key that was spelled last with the adaptive spelling, and its candidates with their scores
+    last_candidates: Option<(u7, Vec<(Pitch31, f64)>)>,
+
+    /// How the last note on was retuned
+    last_decision: Option<Decision>
+}
+
+/// What the processing thread of live mode and replays receives
+pub enum Incoming {
+    /// A raw MIDI message from an input, with its midir timestamp
+    Midi(usize, u64, Vec<u8>),
+
+    /// Saves the tonal space of an input as a named preset
+    SavePreset(usize, String),
+
+    /// Recalls a named preset into the tonal space of an input
+    RecallPreset(usize, String)
+}
+
+/// State kept separately for each MIDI input
+struct InputState {
+    /// Index into `Processor::tonal_spaces`
+    tonal_space: usize,
+
+    control: ControlInterface,
+
+    /// Whether the input is an MPE controller, whose member channels each carry a single note
+    mpe: bool,
+
+    /// Position of the pitch bend wheel on each input channel
+    wheel: [u16; 16],
+
+    /// Last channel pressure and timbre (CC 74) on each member channel of an MPE input.
+    /// MPE controllers send them before the note on, when the output channel is not known yet.
+    pressure: [Option<u7>; 16],
+    timbre: [Option<u7>; 16],
+
+    parser_running_status: Option<u8>
+}
+
+impl Processor {
+    /// Creates a processor without any inputs, see `add_input`
+    pub fn new(config: TonalSpaceConfig, projection_type: ProjectionType, metric: AssonanceMetric,
+               output: OutputConfig) -> Self {
+        Processor {
+            tonal_spaces: vec![TonalSpace::new(config)],
+            config,
+            projection_type,
+            metric,
+            spelling: SpellingMode::Adaptive,
+            inputs: vec![],
+            active_notes: HashMap::new(),
+            output,
+            allocator: ChannelAllocator::new(&output),
+            presets: None,
+            last_candidates: None,
+            last_decision: None
+        }
+    }
+
+    /// Sets where tonal space presets are saved to and recalled from
+    pub fn set_presets(&mut self, presets: Presets) {
+        self.presets = Some(presets);
+    }
+
+    pub fn set_spelling(&mut self, spelling: SpellingMode) {
+        self.spelling = spelling;
+    }
+
+    pub fn config(&self) -> TonalSpaceConfig {
+        self.config
+    }
+
+    /// Frequency of A4 in Hz
+    pub fn reference(&self) -> f64 {
+        self.output.reference()
+    }
+
+    /// Pitch bend range of the output in semitones
+    pub fn bend_range(&self) -> f64 {
+        self.output.bend_range()
+    }
+
+    /// Sends the messages that configure the receiving synth, see `OutputConfig::setup_messages`
+    pub fn start(&self, out: &mut dyn MidiSink) {
+        for message in self.output.setup_messages() {
+            out.send(&message);
+        }
+    }
+
+    /// Adds an input and returns its index. If `own_space` is false, the input shares the
+    /// joint tonal space with all other such inputs, otherwise it is spelled in its own.
+    /// If `mpe` is true, the input is an MPE controller with a lower zone.
+    pub fn add_input(&mut self, own_space: bool, mpe: bool) -> usize {
+        let tonal_space = if own_space {
+            self.tonal_spaces.push(TonalSpace::new(self.config));
+            self.tonal_spaces.len() - 1
+        } else {
+            0
+        };
+
+        self.inputs.push(InputState {
+            tonal_space,
+            control: ControlInterface::default(),
+            mpe,
+            wheel: [output::BEND_CENTRE; 16],
+            pressure: [None; 16],
+            timbre: [None; 16],
+            parser_running_status: None
+        });
+        self.inputs.len() - 1
+    }
+
+    /// Handles a single raw MIDI message from `input`, sending the resulting retuned messages to `out`.
+    ///
+    /// Returns the chosen spelling if the message was a NoteOn.
+    pub fn handle(&mut self, input: usize, raw: &[u8], out: &mut dyn MidiSink) -> Option<Pitch31> {
+        let mut parsed = raw;
+        let ev = match EventKind::parse(&mut parsed, &mut self.inputs[input].parser_running_status) {
+            Ok(event) => event,
             Err(e) => {
-                println!("error parsing midi msg: {}", e);
-                continue;
+                tui::report(format!("error parsing midi msg: {}", e));
+                return None;
             }
-        }
+        };
+
         if let EventKind::Midi {channel, message} = ev {
+            let channel = channel.as_int();
             match message {
-                MidiMessage::NoteOn {key, vel} => {
-                    convert_to_31(key, vel, &mut tonal_space);
+                MidiMessage::NoteOn {key, vel} if vel.as_int() > 0 => {
+                    return Some(self.note_on(input, channel, key, vel, out));
                 }
-                MidiMessage::NoteOff {key, vel} => {
-
+                MidiMessage::NoteOn {key, vel} | MidiMessage::NoteOff {key, vel} => {
+                    self.note_off(input, channel, key, vel, out);
+                }
+                MidiMessage::Controller {controller, value} if controller.as_int() == NEUTRAL_MODIFIER_CC => {
+                    self.inputs[input].control.neutral_held = value.as_int() >= 64;
+                }
+                MidiMessage::Controller {controller, value} if controller.as_int() == PRESET_RECALL_CC => {
+                    match self.recall_preset_number(input, value.as_int() as usize) {
+                        Ok(name) => tui::report(format!("Recalled preset '{}'", name)),
+                        Err(e) => tui::report(format!("Error when recalling preset: {}", e))
+                    }
+                }
+                MidiMessage::Aftertouch {key, vel} => {
+                    if let Some(note) = self.active_notes.get(&(input, channel, key.as_int())) {
+                        let message = MidiMessage::Aftertouch { key: u7::from(note.key), vel };
+                        out.send(&output::on_channel(note.channel, message));
+                    }
+                }
+                MidiMessage::PitchBend {bend} => {
+                    self.pitch_bend_wheel(input, channel, bend.as_int(), out);
+                }
+                MidiMessage::ChannelAftertouch {vel} if self.is_member_channel(input, channel) => {
+                    self.inputs[input].pressure[channel as usize] = Some(vel);
+                    self.send_to_notes(input, channel, message, out);
+                }
+                MidiMessage::Controller {controller, value}
+                    if controller.as_int() == MPE_TIMBRE_CC && self.is_member_channel(input, channel) => {
+                    self.inputs[input].timbre[channel as usize] = Some(value);
+                    self.send_to_notes(input, channel, message, out);
                 }
-                MidiMessage::Controller {controller, value} => {
+                MidiMessage::Controller {..} | MidiMessage::ProgramChange {..} | MidiMessage::ChannelAftertouch {..} => {
+                    match self.output.global_channel() {
+                        Some(ch) => out.send(&output::on_channel(ch, message)),
+                        // Every channel notes could be allocated to needs to be controlled the same way
+                        None => for &ch in self.allocator.channels() {
+                            out.send(&output::on_channel(ch, message));
+                        }
+                    }
+                }
+            }
+            return None;
+        }
+
+        // Everything else (SysEx, realtime messages, ...) is passed through as is
+        out.send(raw);
+        None
+    }
+
+    /// Chooses a spelling for `key` played on `input` and adds it to the input's tonal space
+    pub fn spell(&mut self, input: usize, key: u7) -> Pitch31 {
+        match self.spelling {
+            SpellingMode::Adaptive => {
+                let state = &self.inputs[input];
+                let candidates = spell_with_candidates(key, &mut self.tonal_spaces[state.tonal_space], self.metric,
+                                                       self.projection_type, &state.control);
+                let pitch = candidates[0].0;
+                self.last_candidates = Some((key, candidates));
+                pitch
+            }
+            SpellingMode::Static(chain) => chain.spell(key.as_int())
+        }
+    }
 
+    /// The tonal space `input` is spelled in
+    pub fn tonal_space(&self, input: usize) -> &TonalSpace {
+        &self.tonal_spaces[self.inputs[input].tonal_space]
+    }
+
+    /// Copy of the current state of the tonal space `input` is spelled in
+    pub fn snapshot(&self, input: usize) -> TonalSpace {
+        self.tonal_space(input).clone()
+    }
+
+    /// Spellings of the notes that are currently sounding, lowest first
+    pub fn sounding(&self) -> Vec<Pitch31> {
+        let mut pitches = self.active_notes.values().map(|n| n.pitch).collect::<Vec<_>>();
+        pitches.sort_by_key(|p| p.to_steps_from_a4());
+        pitches
+    }
+
+    /// The key spelled last with the adaptive spelling, and its candidates with their scores (lowest is best)
+    pub fn last_candidates(&self) -> Option<&(u7, Vec<(Pitch31, f64)>)> {
+        self.last_candidates.as_ref()
+    }
+
+    /// How the last note on was spelled and sent, for the decision log. None if it was dropped for lack of a channel.
+    pub fn last_decision(&self) -> Option<&Decision> {
+        self.last_decision.as_ref()
+    }
+
+    /// Replaces the tonal space `input` is spelled in (shared with other inputs in joint harmony)
+    /// by `tonal_space`, e.g. a snapshot
+    pub fn restore(&mut self, input: usize, tonal_space: TonalSpace) {
+        self.tonal_spaces[self.inputs[input].tonal_space] = tonal_space;
+    }
+
+    /// Saves the tonal space of `input` as the preset `name`, returning the file it was written to
+    pub fn save_preset(&self, input: usize, name: &str) -> Result<PathBuf, String> {
+        self.presets.as_ref().ok_or("no preset directory was given")?.save(name, &self.snapshot(input))
+    }
+
+    /// Restores the tonal space of `input` from the preset `name`
+    pub fn recall_preset(&mut self, input: usize, name: &str) -> Result<(), String> {
+        let tonal_space = self.presets.as_ref().ok_or("no preset directory was given")?.load(name, self.config)?;
+        self.restore(input, tonal_space);
+        Ok(())
+    }
+
+    /// Recalls the preset at `index` in alphabetical order, returning its name
+    fn recall_preset_number(&mut self, input: usize, index: usize) -> Result<String, String> {
+        let names = self.presets.as_ref().ok_or("no preset directory was given")?.names()?;
+        let name = names.get(index).ok_or_else(|| format!("there is no preset number {}", index))?.clone();
+        self.recall_preset(input, &name)?;
+        Ok(name)
+    }
+
+    /// The spelling each key of the octave from middle C would get if `input` played it now
+    pub fn mapping(&self, input: usize) -> Vec<Pitch31> {
+        match self.spelling {
+            SpellingMode::Adaptive =>
+                self.tonal_spaces[self.inputs[input].tonal_space].mapping(self.metric, self.projection_type),
+            SpellingMode::Static(chain) => (60..72).map(|key| chain.spell(key)).collect()
+        }
+    }
+
+    /// The spelling the projection table prefers for each key of the octave from middle C
+    pub fn static_mapping(&self) -> Vec<Pitch31> {
+        self.projection_type.static_mapping()
+    }
+
+    fn note_on(&mut self, input: usize, in_channel: u8, key: u7, vel: u7, out: &mut dyn MidiSink) -> Pitch31 {
+        // Retriggering a key that is still sounding releases the old note first
+        self.note_off(input, in_channel, key, u7::from(0), out);
+
+        let tonal_space = self.tonal_space(input).members();
+        let pitch = self.spell(input, key);
+        let candidates = match self.spelling {
+            SpellingMode::Adaptive => self.last_candidates.as_ref().map(|(_, c)| c.clone()).unwrap_or_default(),
+            SpellingMode::Static(_) => vec![]
+        };
+        let (out_key, cents) = self.output.key_policy()
+            .key_and_cents(pitch, key.as_int(), self.output.reference(), self.output.bend_range());
+        let bend = output::cents_to_bend(cents + self.wheel_cents(input, in_channel), self.output.bend_range());
+        let (channel, ended) = match self.allocator.allocate(input, out_key, bend) {
+            Some(allocation) => allocation,
+            None => {
+                // Every channel is retuned for the notes of other inputs
+                tui::report(format!("No channel left for input {}, dropping {}", input, pitch));
+                self.last_decision = None;
+                return pitch;
+            }
+        };
+
+        // Stolen notes, and notes that would be indistinguishable from the new one because they
+        // sound on the same channel and key, are ended
+        let ended = self.active_notes.iter()
+            .filter(|(_, note)| note.channel == channel && ended.contains(&note.key))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for (note_input, note_channel, note_key) in ended {
+            self.note_off(note_input, note_channel, u7::from(note_key), u7::from(0), out);
+        }
+
+        out.send(&output::pitch_bend(channel, bend));
+        if self.is_member_channel(input, in_channel) {
+            let state = &self.inputs[input];
+            if let Some(vel) = state.pressure[in_channel as usize] {
+                out.send(&output::on_channel(channel, MidiMessage::ChannelAftertouch { vel }));
+            }
+            if let Some(value) = state.timbre[in_channel as usize] {
+                let controller = u7::from(MPE_TIMBRE_CC);
+                out.send(&output::on_channel(channel, MidiMessage::Controller { controller, value }));
+            }
+        }
+        out.send(&output::note_on(channel, out_key, vel.as_int()));
+
+        self.active_notes.insert((input, in_channel, key.as_int()), ActiveNote::new(pitch, channel, out_key, cents));
+        self.last_decision = Some(Decision {
+            input,
+            channel: in_channel,
+            key: key.as_int(),
+            velocity: vel.as_int(),
+            candidates,
+            pitch,
+            out_channel: channel,
+            out_key,
+            bend,
+            tonal_space
+        });
+        pitch
+    }
+
+    /// Whether `in_channel` of `input` is an MPE member channel, whose channel wide messages belong to a single note
+    fn is_member_channel(&self, input: usize, in_channel: u8) -> bool {
+        self.inputs[input].mpe && in_channel != MPE_INPUT_MANAGER_CHANNEL
+    }
+
+    /// Sends `message` to the output channels of the notes sounding from `input`'s `in_channel`
+    fn send_to_notes(&self, input: usize, in_channel: u8, message: MidiMessage, out: &mut dyn MidiSink) {
+        for ((note_input, note_channel, _), note) in &self.active_notes {
+            if *note_input == input && *note_channel == in_channel {
+                out.send(&output::on_channel(note.channel, message));
+            }
+        }
+    }
+
+    /// How far the pitch bend wheels of `input` bend notes played on `in_channel`, in cents.
+    ///
+    /// Member channels of an MPE input are bent both by their own wheel, with the MPE bend range,
+    /// and by the wheel of the manager channel.
+    fn wheel_cents(&self, input: usize, in_channel: u8) -> f64 {
+        let wheel = &self.inputs[input].wheel;
+        if self.is_member_channel(input, in_channel) {
+            output::bend_to_cents(wheel[in_channel as usize], output::MPE_BEND_RANGE)
+                + output::bend_to_cents(wheel[MPE_INPUT_MANAGER_CHANNEL as usize], output::BEND_RANGE)
+        } else {
+            output::bend_to_cents(wheel[in_channel as usize], output::BEND_RANGE)
+        }
+    }
+
+    /// Applies the pitch bend wheel on top of the tuning of every note sounding from `input`'s `in_channel`
+    fn pitch_bend_wheel(&mut self, input: usize, in_channel: u8, wheel: u16, out: &mut dyn MidiSink) {
+        self.inputs[input].wheel[in_channel as usize] = wheel;
+        let zone_wide = self.inputs[input].mpe && in_channel == MPE_INPUT_MANAGER_CHANNEL;
+
+        let bends = self.active_notes.iter()
+            .filter(|((note_input, note_channel, _), _)| *note_input == input && (*note_channel == in_channel || zone_wide))
+            .map(|((_, note_channel, _), note)| {
+                let cents = note.cents + self.wheel_cents(input, *note_channel);
+                (note.channel, output::cents_to_bend(cents, self.output.bend_range()))
+            })
+            .collect::<Vec<_>>();
+
+        for (channel, bend) in bends {
+            out.send(&output::pitch_bend(channel, bend));
+            self.allocator.set_bend(channel, bend);
+        }
+    }
+
+    fn note_off(&mut self, input: usize, in_channel: u8, key: u7, vel: u7, out: &mut dyn MidiSink) {
+        if let Some(note) = self.active_notes.remove(&(input, in_channel, key.as_int())) {
+            out.send(&output::note_off(note.channel, note.key, vel.as_int()));
+            self.allocator.release(note.channel, note.key);
+        }
+    }
+}
+
+/// Runs `processor` on everything received from `rx`, sending the output to `out`.
+/// MIDI messages are written to `recorder` if given, and reported to `on_message` once they are
+/// handled, with their input and the spelling if they were a note on, as are saved and recalled presets.
+/// How every note on was spelled is written to `decisions` if given.
+pub(crate) fn process(rx: Receiver<Option<Incoming>>, mut processor: Processor, mut out: impl MidiSink,
+                      mut recorder: Option<Recorder>, mut decisions: Option<DecisionLog>,
+                      mut on_message: impl FnMut(&Processor, usize, Option<Pitch31>)) {
+    // Once None is sent, app will be terminated
+    while let Ok(Some(incoming)) = rx.recv() {
+        match incoming {
+            Incoming::Midi(input, stamp, raw) => {
+                if let Some(recorder) = &mut recorder {
+                    recorder.record(input, stamp, &raw);
+                }
+                let pitch = processor.handle(input, &raw, &mut out);
+                if let (Some(decisions), Some(decision)) = (&mut decisions, pitch.and(processor.last_decision())) {
+                    decisions.write(stamp, decision);
+                }
+                on_message(&processor, input, pitch);
+            }
+            Incoming::SavePreset(input, name) => {
+                match processor.save_preset(input, &name) {
+                    Ok(path) => tui::report(format!("Saved preset '{}' to {}", name, path.display())),
+                    Err(e) => tui::report(format!("Error when saving preset: {}", e))
+                }
+                on_message(&processor, input, None);
+            }
+            Incoming::RecallPreset(input, name) => {
+                match processor.recall_preset(input, &name) {
+                    Ok(()) => tui::report(format!("Recalled preset '{}'", name)),
+                    Err(e) => tui::report(format!("Error when recalling preset: {}", e))
                 }
-                _ => ()
+                on_message(&processor, input, None);
             }
         }
     }
 }
 
-pub fn convert_to_31(key: u7, vel: u7, tonal_space: &mut TonalSpace) {
+pub fn convert_to_31(key: u7, tonal_space: &mut TonalSpace, am: AssonanceMetric, pt: ProjectionType,
+                     control: &ControlInterface) -> Pitch31 {
+    spell_with_candidates(key, tonal_space, am, pt, control)[0].0
+}
+
+/// Like `convert_to_31`, but returns all candidates with their scores, the chosen one first
+fn spell_with_candidates(key: u7, tonal_space: &mut TonalSpace, am: AssonanceMetric, pt: ProjectionType,
+                         control: &ControlInterface) -> Vec<(Pitch31, f64)> {
+    let pt = if control.neutral_held {
+        ProjectionType::Meantone31Neutral
+    } else {
+        pt
+    };
+
+    let mut candidates = tonal_space.convert_to_31(key, am, pt);
+    if candidates.is_empty() {
+        // Only an empty tonal space has no candidates, which spells from the projection table alone
+        candidates.push((pt.static_spelling(key), 0.0));
+    }
+    tonal_space.insert(candidates[0].0, key);
+
+    candidates
+}
+
+/// How notes are spelled
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SpellingMode {
+    /// According to the harmonic context, see `TonalSpace`
+    Adaptive,
+
+    /// Always the same way for each pitch class, as a baseline to compare the adaptive spelling with
+    Static(FifthsChain)
+}
 
+#[derive(Default)]
+pub struct ControlInterface {
+    /// Whether the neutral modifier (see `NEUTRAL_MODIFIER_CC`) is currently held down
+    neutral_held: bool
 }
 
-struct ControlInterface {
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::OutputMode;
+
+    fn processor_with(mode: OutputMode, mpe_input: bool) -> (Processor, usize) {
+        let mut p = Processor::new(TonalSpaceConfig::default(), ProjectionType::Meantone31KeepUnison,
+                                   AssonanceMetric::Pythagorean, OutputConfig::new(mode));
+        let input = p.add_input(false, mpe_input);
+        (p, input)
+    }
+
+    fn processor() -> (Processor, usize) {
+        processor_with(OutputMode::PerNoteChannel, false)
+    }
+
+    #[test]
+    fn controllers_are_mirrored_to_all_voice_channels() {
+        let (mut p, input) = processor();
+        let mut out: Vec<Vec<u8>> = vec![];
+        p.handle(input, &[0xB0, 1, 100], &mut out);
+
+        assert_eq!(out.len(), p.allocator.channels().len());
+        for (msg, ch) in out.iter().zip(p.allocator.channels()) {
+            assert_eq!(msg, &vec![0xB0 | ch, 1, 100]);
+        }
+    }
+
+    #[test]
+    fn wheel_is_added_to_tuning_bend() {
+        let (mut p, input) = processor();
+        let mut out: Vec<Vec<u8>> = vec![];
+        p.handle(input, &[0x90, 60, 100], &mut out);
+        let tuning = output::cents_to_bend(p.active_notes[&(input, 0, 60)].cents, output::BEND_RANGE);
+        let channel = p.active_notes[&(input, 0, 60)].channel;
 
-}
\ No newline at end of file
+        out.clear();
+        // Wheel 0x1000 below centre
+        p.handle(input, &[0xE0, 0x00, 0x20], &mut out);
+        assert_eq!(out, vec![output::pitch_bend(channel, tuning - 0x1000).to_vec()]);
+    }
+
+    #[test]
+    fn mpe_expression_follows_note() {
+        let (mut p, input) = processor_with(OutputMode::mpe(4).unwrap(), true);
+        let mut out: Vec<Vec<u8>> = vec![];
+
+        // Pressure and timbre on input member channel 3 arrive before the note
+        p.handle(input, &[0xD2, 50], &mut out);
+        p.handle(input, &[0xB2, 74, 90], &mut out);
+        assert!(out.is_empty());
+
+        p.handle(input, &[0x92, 60, 100], &mut out);
+        let channel = p.active_notes[&(input, 2, 60)].channel;
+        assert!((1..=4).contains(&channel));
+        assert_eq!(&out[1..], &[vec![0xD0 | channel, 50], vec![0xB0 | channel, 74, 90], vec![0x90 | channel, 60, 100]]);
+
+        // Later pressure only reaches the note's channel, global controllers only the manager channel
+        out.clear();
+        p.handle(input, &[0xD2, 70], &mut out);
+        p.handle(input, &[0xB0, 1, 20], &mut out);
+        assert_eq!(out, vec![vec![0xD0 | channel, 70], vec![0xB0, 1, 20]]);
+    }
+
+    #[test]
+    fn presets_restore_tonal_space() {
+        let dir = std::env::temp_dir().join(format!("31from12-presets-{}", std::process::id()));
+        let (mut p, input) = processor();
+        p.set_presets(Presets::open(&dir).unwrap());
+        let mut out: Vec<Vec<u8>> = vec![];
+
+        // Eb major, then a long stretch of sharp keys
+        for key in [63, 67, 70] {
+            p.handle(input, &[0x90, key, 100], &mut out);
+        }
+        let eb_major = p.snapshot(input).mapping(AssonanceMetric::Pythagorean, ProjectionType::Meantone31KeepUnison);
+        p.save_preset(input, "b-movement 2").unwrap();
+        p.save_preset(input, "a-start").unwrap();
+        for key in [66, 70, 61, 66, 68, 63, 70, 66] {
+            p.handle(input, &[0x90, key, 100], &mut out);
+        }
+        let sharp = p.snapshot(input);
+        assert_ne!(sharp.mapping(AssonanceMetric::Pythagorean, ProjectionType::Meantone31KeepUnison), eb_major);
+
+        p.recall_preset(input, "b-movement 2").unwrap();
+        assert_eq!(p.snapshot(input).mapping(AssonanceMetric::Pythagorean, ProjectionType::Meantone31KeepUnison), eb_major);
+
+        // The controller numbers presets alphabetically
+        p.restore(input, sharp);
+        out.clear();
+        p.handle(input, &[0xB0, PRESET_RECALL_CC, 1], &mut out);
+        assert!(out.is_empty());
+        assert_eq!(p.snapshot(input).mapping(AssonanceMetric::Pythagorean, ProjectionType::Meantone31KeepUnison), eb_major);
+
+        assert!(p.recall_preset(input, "missing").is_err());
+        assert!(p.save_preset(input, "../escape").is_err());
+
+        // An empty tonal space spells from the projection table instead of failing
+        p.restore(input, TonalSpace::default());
+        assert_eq!(p.handle(input, &[0x90, 64, 100], &mut out), Some(Pitch31::new("E4").unwrap()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn no_two_notes_on_same_channel_and_key() {
+        let (mut p, input) = processor_with(OutputMode::mpe(1).unwrap(), false);
+        let mut out: Vec<Vec<u8>> = vec![];
+        p.handle(input, &[0x90, 60, 100], &mut out);
+        out.clear();
+
+        // The same key on another input channel can only go to the single member channel
+        p.handle(input, &[0x91, 60, 100], &mut out);
+        assert_eq!(out[0], vec![0x81, 60, 0]);
+        assert_eq!(p.active_notes.len(), 1);
+    }
+}
@@ -0,0 +1,332 @@
+use std::convert::TryFrom;
+use std::fs;
+use std::path::Path;
+use std::sync::RwLock;
+
+use lazy_static::lazy_static;
+use toml::Value;
+
+use crate::tonal_space::ProjectionType;
+
+/// A 12 edo interval can be projected to a 31 edo interval no more than this many
+/// steps away from its 12 edo size (31 / 12 ≈ 2.58 steps, i.e. one 12 edo semitone)
+const MAX_STEPS_DEVIATION: f64 = 31.0 / 12.0;
+
+lazy_static! {
+    /// Projection tables loaded at runtime, indexed by `ProjectionType::Custom`
+    static ref CUSTOM_TABLES: RwLock<Vec<ProjectionTable>> = RwLock::new(vec![]);
+}
+
+/// Prior of the preferred candidate(s) of a semitone class
+const PREFERRED: f64 = 0.0;
+
+/// Prior of the v/^ variants of P4/5 and Maj2/3/6/7, and of neutral intervals.
+/// Should only be chosen when the tonal space clearly favours them.
+const ALTERED: f64 = 2.0;
+
+// Built-in tables. Index = 12 edo semitones above the tonal space note,
+// values = (31 edo steps above the tonal space note, prior).
+
+const MEANTONE_17: [&[(i16, f64)]; 12] = [
+    &[(0, PREFERRED)],
+    &[(2, PREFERRED), (3, PREFERRED)],
+    &[(5, PREFERRED)],
+    &[(7, PREFERRED), (8, PREFERRED)],
+    &[(10, PREFERRED)],
+    &[(13, PREFERRED)],
+    &[(15, PREFERRED), (16, PREFERRED)],
+    &[(18, PREFERRED)],
+    &[(20, PREFERRED), (21, PREFERRED)],
+    &[(23, PREFERRED)],
+    &[(25, PREFERRED), (26, PREFERRED)],
+    &[(28, PREFERRED)],
+];
+
+const MEANTONE_31_KEEP_UNISON: [&[(i16, f64)]; 12] = [
+    &[(0, PREFERRED)],
+    &[(2, PREFERRED), (3, PREFERRED)],
+    &[(5, PREFERRED), (4, ALTERED), (6, ALTERED)],
+    &[(7, PREFERRED), (8, PREFERRED)],
+    &[(10, PREFERRED), (9, ALTERED), (11, ALTERED)],
+    &[(13, PREFERRED), (12, ALTERED), (14, ALTERED)],
+    &[(15, PREFERRED), (16, PREFERRED)],
+    &[(18, PREFERRED), (17, ALTERED), (19, ALTERED)],
+    &[(20, PREFERRED), (21, PREFERRED)],
+    &[(23, PREFERRED), (22, ALTERED), (24, ALTERED)],
+    &[(25, PREFERRED), (26, PREFERRED)],
+    &[(28, PREFERRED), (27, ALTERED), (29, ALTERED)],
+];
+
+const MEANTONE_31_NEUTRAL: [&[(i16, f64)]; 12] = [
+    &[(0, PREFERRED)],
+    &[(2, PREFERRED), (3, PREFERRED), (4, ALTERED)],
+    &[(5, PREFERRED), (4, ALTERED), (6, ALTERED)],
+    &[(7, PREFERRED), (8, PREFERRED), (9, ALTERED)],
+    &[(10, PREFERRED), (9, ALTERED), (11, ALTERED)],
+    &[(13, PREFERRED), (12, ALTERED), (14, ALTERED)],
+    &[(15, PREFERRED), (16, PREFERRED)],
+    &[(18, PREFERRED), (17, ALTERED), (19, ALTERED)],
+    &[(20, PREFERRED), (21, PREFERRED), (22, ALTERED)],
+    &[(23, PREFERRED), (22, ALTERED), (24, ALTERED)],
+    &[(25, PREFERRED), (26, PREFERRED), (27, ALTERED)],
+    &[(28, PREFERRED), (27, ALTERED), (29, ALTERED)],
+];
+
+/// A possible 31 edo realisation of a 12 edo interval
+#[derive(Copy, Clone)]
+pub struct Candidate {
+    /// Number of 31 edo steps above the tonal space note (within one octave)
+    pub steps: i16,
+
+    /// Cost of choosing this candidate before the assonance score is taken into account.
+    /// Added to the assonance coefficient, so it is on the same scale as the selected
+    /// `AssonanceMetric` (e.g. number of fifths for Pythagorean)
+    pub prior: f64
+}
+
+/// Maps each 12 edo semitone class to the 31 edo intervals it can be projected onto
+pub struct ProjectionTable {
+    pub name: String,
+    classes: Vec<Vec<Candidate>>
+}
+
+impl ProjectionTable {
+    fn builtin(name: &str, classes: &[&[(i16, f64)]; 12]) -> Self {
+        ProjectionTable {
+            name: name.to_owned(),
+            classes: classes.iter()
+                .map(|cands| cands.iter().map(|&(steps, prior)| Candidate { steps, prior }).collect())
+                .collect()
+        }
+    }
+
+    /// Candidates for an interval of `semitones` (0 to 11) 12 edo semitones
+    pub fn candidates(&self, semitones: i16) -> &[Candidate] {
+        &self.classes[semitones as usize]
+    }
+
+    /// Parses a single table from the TOML table `value`. Keys are semitone classes `0` to `11`,
+    /// and each maps to an array of candidates. A candidate is either the number of steps,
+    /// or an inline table `{ steps = 9, prior = 1.5 }`.
+    fn from_toml(name: &str, value: &Value) -> Result<Self, String> {
+        let table = value.as_table()
+            .ok_or(format!("'{}' must be a table of semitone classes, found {}", name, value.type_str()))?;
+
+        let mut classes = vec![None; 12];
+
+        for (key, cands) in table {
+            let semitones = key.parse::<usize>().ok()
+                .filter(|s| *s < 12)
+                .ok_or(format!("'{}': '{}' is not a semitone class (expected 0 to 11)", name, key))?;
+
+            let cands = cands.as_array()
+                .ok_or(format!("'{}': semitone class {} must be an array of candidates, found {}",
+                               name, semitones, cands.type_str()))?;
+
+            if cands.is_empty() {
+                return Err(format!("'{}': semitone class {} has no candidates", name, semitones));
+            }
+
+            let mut parsed: Vec<Candidate> = vec![];
+            for (i, cand) in cands.iter().enumerate() {
+                let cand = parse_candidate(cand)
+                    .map_err(|e| format!("'{}': semitone class {}, candidate {}: {}", name, semitones, i + 1, e))?;
+
+                let deviation = (cand.steps as f64 - semitones as f64 * 31.0 / 12.0).abs();
+                if deviation > MAX_STEPS_DEVIATION {
+                    return Err(format!("'{}': semitone class {}, candidate {}: {} steps is too far from {} semitones",
+                                       name, semitones, i + 1, cand.steps, semitones));
+                }
+                if parsed.iter().any(|c| c.steps == cand.steps) {
+                    return Err(format!("'{}': semitone class {}: {} steps is listed more than once",
+                                       name, semitones, cand.steps));
+                }
+                parsed.push(cand);
+            }
+
+            classes[semitones] = Some(parsed);
+        }
+
+        let missing = (0..12).filter(|s| classes[*s].is_none()).map(|s| s.to_string()).collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(format!("'{}': missing semitone classes {}", name, missing.join(", ")));
+        }
+
+        Ok(ProjectionTable {
+            name: name.to_owned(),
+            classes: classes.into_iter().map(Option::unwrap).collect()
+        })
+    }
+}
+
+fn parse_candidate(value: &Value) -> Result<Candidate, String> {
+    match value {
+        Value::Integer(steps) => Ok(Candidate { steps: parse_steps(*steps)?, prior: 0.0 }),
+        Value::Table(table) => {
+            for key in table.keys() {
+                if key != "steps" && key != "prior" {
+                    return Err(format!("unknown field '{}' (expected 'steps' or 'prior')", key));
+                }
+            }
+            let steps = match table.get("steps") {
+                Some(Value::Integer(steps)) => parse_steps(*steps)?,
+                Some(v) => return Err(format!("steps must be an integer, found {}", v.type_str())),
+                None => return Err("missing field 'steps'".to_owned())
+            };
+            let prior = match table.get("prior") {
+                Some(Value::Float(prior)) if prior.is_finite() => *prior,
+                Some(Value::Float(prior)) => return Err(format!("prior must be finite, found {}", prior)),
+                Some(Value::Integer(prior)) => *prior as f64,
+                Some(v) => return Err(format!("prior must be a number, found {}", v.type_str())),
+                None => 0.0
+            };
+            Ok(Candidate { steps, prior })
+        }
+        v => Err(format!("expected number of steps or {{ steps, prior }} table, found {}", v.type_str()))
+    }
+}
+
+fn parse_steps(steps: i64) -> Result<i16, String> {
+    i16::try_from(steps).map_err(|_| format!("{} steps is out of range", steps))
+}
+
+/// Parses projection tables from TOML source. Each top level table defines a projection type:
+///
+/// ```toml
+/// [Maqam]
+/// 0 = [0]
+/// 1 = [2, 3, 4]
+/// 3 = [8, { steps = 9, prior = -1.0 }]
+/// # ... all semitone classes from 0 to 11 must be present
+/// ```
+pub fn parse_tables(source: &str) -> Result<Vec<ProjectionTable>, String> {
+    let root = source.parse::<Value>().map_err(|e| e.to_string())?;
+    let root = root.as_table().ok_or("expected a table of projection types")?;
+
+    let mut tables = vec![];
+    for (name, value) in root {
+        if ProjectionType::from_name(name).is_some() {
+            return Err(format!("'{}': a projection type with this name already exists", name));
+        }
+        tables.push(ProjectionTable::from_toml(name, value)?);
+    }
+    Ok(tables)
+}
+
+/// Loads projection tables from a TOML file and registers them.
+/// Returns the projection types that can be used to refer to the new tables.
+pub fn load_tables<P: AsRef<Path>>(path: P) -> Result<Vec<ProjectionType>, String> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    let tables = parse_tables(&source)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    Ok(tables.into_iter().map(register).collect())
+}
+
+/// Registers a projection table, making it available as a `ProjectionType::Custom`
+pub fn register(table: ProjectionTable) -> ProjectionType {
+    let mut custom = CUSTOM_TABLES.write().unwrap();
+    custom.push(table);
+    ProjectionType::Custom(custom.len() - 1)
+}
+
+/// Finds a registered custom projection type by name
+pub fn find_custom(name: &str) -> Option<ProjectionType> {
+    CUSTOM_TABLES.read().unwrap()
+        .iter()
+        .position(|t| t.name == name)
+        .map(ProjectionType::Custom)
+}
+
+/// All registered custom projection types
+pub fn custom_types() -> Vec<ProjectionType> {
+    (0..CUSTOM_TABLES.read().unwrap().len()).map(ProjectionType::Custom).collect()
+}
+
+/// Calls `f` with the projection table for `projection_type`
+pub fn with_table<T>(projection_type: ProjectionType, f: impl FnOnce(&ProjectionTable) -> T) -> T {
+    lazy_static! {
+        static ref BUILTIN: Vec<ProjectionTable> = vec![
+            ProjectionTable::builtin("Meantone17", &MEANTONE_17),
+            ProjectionTable::builtin("Meantone31KeepUnison", &MEANTONE_31_KEEP_UNISON),
+            ProjectionTable::builtin("Meantone31Neutral", &MEANTONE_31_NEUTRAL),
+        ];
+    }
+
+    match projection_type {
+        ProjectionType::Meantone17 => f(&BUILTIN[0]),
+        ProjectionType::Meantone31KeepUnison => f(&BUILTIN[1]),
+        ProjectionType::Meantone31Neutral => f(&BUILTIN[2]),
+        ProjectionType::Custom(idx) => f(&CUSTOM_TABLES.read().unwrap()[idx])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::theory::Pitch31;
+    use crate::tonal_space::{AssonanceMetric, TonalSpace, TonalSpaceConfig};
+    use midly::number::u7;
+
+    /// TOML of a table `name` with the candidates of Meantone17, except for semitone class
+    /// `semitones`, which has the TOML value `class`, or is left out if it is empty
+    fn source(name: &str, semitones: usize, class: &str) -> String {
+        let mut source = format!("[{}]\n", name);
+        for (s, cands) in MEANTONE_17.iter().enumerate() {
+            if s != semitones {
+                let steps = cands.iter().map(|(steps, _)| steps.to_string()).collect::<Vec<_>>();
+                source += &format!("{} = [{}]\n", s, steps.join(", "));
+            } else if !class.is_empty() {
+                source += &format!("{} = {}\n", s, class);
+            }
+        }
+        source
+    }
+
+    fn error(source: &str) -> String {
+        match parse_tables(source) {
+            Ok(_) => panic!("expected an error for:\n{}", source),
+            Err(e) => e
+        }
+    }
+
+    #[test]
+    fn parse_valid_tables() {
+        let tables = parse_tables(&source("Valid", 3, "[7, { steps = 8, prior = 1.5 }, { steps = 9, prior = 2 }]"))
+            .unwrap();
+        assert_eq!(tables.len(), 1);
+        let steps = tables[0].candidates(3).iter().map(|c| (c.steps, c.prior)).collect::<Vec<_>>();
+        assert_eq!(steps, vec![(7, 0.0), (8, 1.5), (9, 2.0)]);
+    }
+
+    #[test]
+    fn reject_invalid_tables() {
+        assert!(error(&source("Missing", 4, "")).contains("missing semitone classes 4"));
+        assert!(error(&source("Duplicate", 1, "[2, 3, 2]")).contains("2 steps is listed more than once"));
+        assert!(error(&source("TooFar", 2, "[9]")).contains("9 steps is too far from 2 semitones"));
+        assert!(error(&source("Overflow", 2, "[40000]")).contains("40000 steps is out of range"));
+        assert!(error(&source("UnknownField", 2, "[{ steps = 5, weight = 1.0 }]")).contains("unknown field 'weight'"));
+        assert!(error(&source("NotArray", 2, "5")).contains("must be an array of candidates, found integer"));
+        assert!(error(&source("Empty", 2, "[]")).contains("has no candidates"));
+        assert!(error(&source("Infinite", 2, "[{ steps = 5, prior = inf }]")).contains("prior must be finite"));
+        assert!(error(&source("NaN", 2, "[{ steps = 5, prior = nan }]")).contains("prior must be finite"));
+        assert!(error(&source("Meantone17", 12, "")).contains("a projection type with this name already exists"));
+        assert!(error("[BadClass]\n12 = [31]\n").contains("'12' is not a semitone class"));
+    }
+
+    #[test]
+    fn convert_with_custom_table() {
+        // Like Meantone17, but the tritone is always spelled as a diminished fifth
+        let table = parse_tables(&source("TritoneIsDiminished", 6, "[16]")).unwrap().pop().unwrap();
+        let custom = register(table);
+        assert_eq!(ProjectionType::from_name("TritoneIsDiminished").map(ProjectionType::name),
+                   Some(custom.name()));
+
+        let ts = TonalSpace::new(TonalSpaceConfig::default());
+        let best = |pt| ts.convert_to_31(u7::from(66), AssonanceMetric::Pythagorean, pt)[0].0;
+        assert_eq!(best(ProjectionType::Meantone17), Pitch31::new("F#4").unwrap());
+        assert_eq!(best(custom), Pitch31::new("Gb4").unwrap());
+    }
+}
@@ -0,0 +1,150 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::tui;
+
+/// Recordings are text files with one incoming message per line: the index of the input, the time
+/// it was received in microseconds (on a clock shared by all inputs) and the raw bytes in hex,
+/// e.g. `0 1520311 90 3C 64`. They start with one `input <index> joint|own` line per input, giving
+/// its tonal space, and one `setting <option> [value]` line per command line option the retuner
+/// was run with, e.g. `setting --projection Meantone17`. Lines starting with # are comments.
+const HEADER: &str = "# 31 from 12 recording: <input> <timestamp in us> <message bytes>";
+
+/// A message as it was received by the live retuner
+#[derive(Debug, PartialEq)]
+pub struct Recorded {
+    pub input: usize,
+    pub stamp: u64,
+    pub message: Vec<u8>
+}
+
+/// A recording that was loaded from a file
+pub struct Recording {
+    /// For each input, whether it was spelled in its own tonal space
+    pub own_spaces: Vec<bool>,
+
+    /// Command line options of the retuner, each with its value if it takes one, e.g. "--mpe 15"
+    pub settings: Vec<String>,
+
+    pub messages: Vec<Recorded>
+}
+
+/// Writes incoming messages to a recording file. Every message is flushed right away, so that
+/// nothing is lost when live mode is killed.
+pub struct Recorder {
+    file: BufWriter<File>
+}
+
+impl Recorder {
+    /// Creates the recording file `path` for inputs with the given tonal spaces (true = own space),
+    /// and a retuner run with the command line options `settings` (see `Recording::settings`)
+    pub fn create<P: AsRef<Path>>(path: P, own_spaces: &[bool], settings: &[String]) -> Result<Self, String> {
+        let path = path.as_ref();
+        let file = File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut recorder = Recorder { file: BufWriter::new(file) };
+
+        let mut header = vec![HEADER.to_owned()];
+        for (i, own) in own_spaces.iter().enumerate() {
+            header.push(format!("input {} {}", i, if *own { "own" } else { "joint" }));
+        }
+        header.extend(settings.iter().map(|s| format!("setting {}", s)));
+        for line in header {
+            writeln!(recorder.file, "{}", line).map_err(|e| e.to_string())?;
+        }
+        recorder.file.flush().map_err(|e| e.to_string())?;
+        Ok(recorder)
+    }
+
+    pub fn record(&mut self, input: usize, stamp: u64, message: &[u8]) {
+        let bytes = message.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>();
+        writeln!(self.file, "{} {} {}", input, stamp, bytes.join(" "))
+            .and_then(|_| self.file.flush())
+            .unwrap_or_else(|e| tui::report(format!("Error when recording message: {}", e)));
+    }
+}
+
+/// Loads a recording written by `Recorder`, with its messages ordered by their stamps
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Recording, String> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    parse(&source).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+pub fn parse(source: &str) -> Result<Recording, String> {
+    let mut recording = Recording { own_spaces: vec![], settings: vec![], messages: vec![] };
+
+    for (i, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |e: &str| format!("line {}: {}", i + 1, e);
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+
+        if fields[0] == "input" {
+            match fields[1..] {
+                [idx, space] if idx.parse() == Ok(recording.own_spaces.len()) => {
+                    let own = match space {
+                        "own" => true,
+                        "joint" => false,
+                        _ => return Err(error("expected 'own' or 'joint'"))
+                    };
+                    recording.own_spaces.push(own);
+                }
+                _ => return Err(error("expected 'input <index> own|joint', with inputs numbered in order"))
+            }
+            continue;
+        }
+        if fields[0] == "setting" {
+            match line.split_once(' ') {
+                Some((_, option)) if option.trim_start().starts_with("--") =>
+                    recording.settings.push(option.trim_start().to_owned()),
+                _ => return Err(error("expected 'setting --<option> [value]'"))
+            }
+            continue;
+        }
+
+        if fields.len() < 3 {
+            return Err(error("expected <input> <timestamp> <message bytes>"));
+        }
+        let input = fields[0].parse::<usize>().map_err(|_| error("invalid input index"))?;
+        if input >= recording.own_spaces.len() {
+            return Err(error(&format!("input {} is not declared", input)));
+        }
+        let stamp = fields[1].parse::<u64>().map_err(|_| error("invalid timestamp"))?;
+        let message = fields[2..].iter()
+            .map(|b| u8::from_str_radix(b, 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| error("invalid message byte"))?;
+        recording.messages.push(Recorded { input, stamp, message });
+    }
+    // Inputs are received on their own threads, so their messages can be written slightly out of order.
+    // The sort is stable, so messages with the same stamp keep their order.
+    recording.messages.sort_by_key(|m| m.stamp);
+    Ok(recording)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_recording() {
+        let recording = parse("# comment\ninput 0 joint\ninput 1 own\nsetting --presets my presets\nsetting --mpe-in\n\
+                               0 100 90 3C 64\n1 250 E0 00 40\n").unwrap();
+        assert_eq!(recording.own_spaces, vec![false, true]);
+        assert_eq!(recording.settings, vec!["--presets my presets", "--mpe-in"]);
+        assert_eq!(recording.messages[1], Recorded { input: 1, stamp: 250, message: vec![0xE0, 0x00, 0x40] });
+
+        let recording = parse("input 0 joint\ninput 1 joint\n0 300 90 3C 64\n1 200 90 40 64\n0 300 80 3C 00\n\
+                               1 100 B0 40 7F\n").unwrap();
+        assert_eq!(recording.messages.iter().map(|m| (m.stamp, m.message[0])).collect::<Vec<_>>(),
+                   vec![(100, 0xB0), (200, 0x90), (300, 0x90), (300, 0x80)]);
+
+        assert!(parse("input 0 joint\n1 100 90 3C 64\n").is_err());
+        assert!(parse("input 0 joint\n0 100 9G\n").is_err());
+        assert!(parse("input 1 own\n").is_err());
+        assert!(parse("setting mpe\n").is_err());
+    }
+}
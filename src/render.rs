@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fs;
+use std::path::Path;
+
+use midly::{EventKind, MidiMessage};
+
+use crate::output::{self, Discard};
+use crate::processor::Processor;
+use crate::record::Recorded;
+use crate::theory::{self, Pitch31};
+
+pub const SAMPLE_RATE: u32 = 44100;
+
+/// Number of harmonics of the synth. Many of them make mistuned thirds beat clearly.
+const HARMONICS: u32 = 16;
+
+// Envelope of every note, in seconds
+const ATTACK: f64 = 0.01;
+const DECAY: f64 = 1.5;
+const RELEASE: f64 = 0.15;
+
+/// Level of a single note at full velocity. Leaves headroom for chords before the mix is normalized.
+const NOTE_LEVEL: f64 = 0.15;
+
+/// A spelled note to render, with its start and end in seconds
+#[derive(Debug, PartialEq)]
+pub struct RenderedNote {
+    pub start: f64,
+    pub end: f64,
+    pub pitch: Pitch31,
+    pub velocity: u8
+}
+
+/// Spells messages (input, time in seconds, raw MIDI message) with `processor`, returning the notes they play.
+/// The sustain pedal is not taken into account. Notes still held at the end stop with the last message.
+pub fn spell_notes(processor: &mut Processor, messages: Vec<(usize, f64, Vec<u8>)>) -> Vec<RenderedNote> {
+    collect_notes(messages, |input, raw| processor.handle(input, raw, &mut Discard))
+}
+
+/// Messages (input, time in seconds, raw MIDI message) of a recording, ordered by their stamps, as messages
+/// of different inputs are not necessarily recorded in that order, with the time counted from the first one
+pub fn recorded_messages(mut messages: Vec<Recorded>) -> Vec<(usize, f64, Vec<u8>)> {
+    messages.sort_by_key(|m| m.stamp);
+    let first_stamp = messages.first().map(|m| m.stamp).unwrap_or(0);
+    messages.into_iter()
+        .map(|m| (m.input, (m.stamp - first_stamp) as f64 / 1e6, m.message))
+        .collect()
+}
+
+/// Reads the notes of messages (time in seconds, raw MIDI message) that were already retuned, e.g. by
+/// the convert command, rather than spelling them again. Each note gets the 31 edo pitch (with A4 at
+/// `reference` Hz) nearest to what its key sounds at on a synth tuned to 440 Hz, bent by the pitch bend
+/// its channel had at the note on, with a bend range of `bend_range` semitones.
+pub fn decode_notes(messages: Vec<(f64, Vec<u8>)>, bend_range: f64, reference: f64) -> Vec<RenderedNote> {
+    let mut bends = [output::BEND_CENTRE; 16];
+    let messages = messages.into_iter().map(|(time, raw)| (0, time, raw)).collect();
+    collect_notes(messages, |_, raw| match EventKind::parse(&mut &raw[..], &mut None) {
+        Ok(EventKind::Midi {channel, message: MidiMessage::PitchBend {bend}}) => {
+            bends[channel.as_int() as usize] = bend.as_int();
+            None
+        }
+        Ok(EventKind::Midi {channel, message: MidiMessage::NoteOn {key, vel}}) if vel.as_int() > 0 => {
+            let cents = (key.as_int() as f64 - 69.0) * 100.0
+                + output::bend_to_cents(bends[channel.as_int() as usize], bend_range);
+            let frequency = theory::STANDARD_REFERENCE * 2f64.powf(cents / 1200.0);
+            Some(theory::nearest_pitch31(frequency, reference))
+        }
+        _ => None
+    })
+}
+
+/// Returns the notes played by messages (input, time in seconds, raw MIDI message), where `pitch` gives
+/// the pitch of a note on message, or None for any other message
+fn collect_notes(messages: Vec<(usize, f64, Vec<u8>)>, mut pitch: impl FnMut(usize, &[u8]) -> Option<Pitch31>)
+    -> Vec<RenderedNote> {
+    let mut notes: Vec<RenderedNote> = vec![];
+    // Index into notes of the sounding notes, by input, channel and key
+    let mut sounding = HashMap::new();
+    let end = messages.last().map(|(_, time, _)| *time).unwrap_or(0.0);
+
+    for (input, time, raw) in messages {
+        let pitch = pitch(input, &raw);
+        let (channel, message) = match EventKind::parse(&mut raw.as_slice(), &mut None) {
+            Ok(EventKind::Midi {channel, message}) => (channel.as_int(), message),
+            _ => continue
+        };
+
+        match (message, pitch) {
+            (MidiMessage::NoteOn {key, vel}, Some(pitch)) => {
+                if let Some(idx) = sounding.insert((input, channel, key.as_int()), notes.len()) {
+                    notes[idx].end = time;
+                }
+                notes.push(RenderedNote { start: time, end, pitch, velocity: vel.as_int() });
+            }
+            (MidiMessage::NoteOn {key, ..}, None) | (MidiMessage::NoteOff {key, ..}, _) => {
+                if let Some(idx) = sounding.remove(&(input, channel, key.as_int())) {
+                    notes[idx].end = time;
+                }
+            }
+            _ => ()
+        }
+    }
+    notes
+}
+
+/// Renders `notes` with an additive synth (a sawtooth-like spectrum) tuned to 31 edo with A4 at
+/// `reference` Hz, returning mono samples between -1 and 1
+pub fn render(notes: &[RenderedNote], reference: f64) -> Vec<f32> {
+    let length = notes.iter().map(|n| n.start.max(n.end) + RELEASE).fold(0.0, f64::max);
+    let mut mix = vec![0.0f64; (length * SAMPLE_RATE as f64).ceil() as usize];
+
+    for note in notes {
+        let freq = note.pitch.frequency(reference);
+        let level = NOTE_LEVEL * note.velocity as f64 / 127.0;
+        let first = (note.start * SAMPLE_RATE as f64) as usize;
+        let duration = (note.end - note.start).max(0.0);
+        let harmonics = (1..=HARMONICS)
+            .filter(|h| freq * *h as f64 * 2.0 < SAMPLE_RATE as f64)
+            .collect::<Vec<_>>();
+
+        for (i, sample) in mix[first..].iter_mut().enumerate() {
+            let t = i as f64 / SAMPLE_RATE as f64;
+            if t >= duration + RELEASE {
+                break;
+            }
+            let wave = harmonics.iter()
+                .map(|h| (2.0 * PI * freq * *h as f64 * t).sin() / *h as f64)
+                .sum::<f64>();
+            *sample += level * envelope(t, duration) * wave;
+        }
+    }
+
+    // Only turns the mix down, so that quiet pieces stay quiet
+    let peak = mix.iter().fold(0.0, |peak: f64, s| peak.max(s.abs()));
+    let gain = if peak > 0.99 { 0.99 / peak } else { 1.0 };
+    mix.into_iter().map(|s| (s * gain) as f32).collect()
+}
+
+/// Amplitude `t` seconds into a note held for `duration` seconds
+fn envelope(t: f64, duration: f64) -> f64 {
+    let held = |t: f64| (t / ATTACK).min(1.0) * (-t / DECAY).exp();
+    if t < duration {
+        held(t)
+    } else {
+        held(duration) * (1.0 - (t - duration) / RELEASE).max(0.0)
+    }
+}
+
+/// Encodes mono samples as a 16 bit PCM WAV file
+pub fn wav(samples: &[f32]) -> Vec<u8> {
+    let data_size = samples.len() as u32 * 2;
+    let mut wav = Vec::with_capacity(44 + data_size as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_size).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    // PCM, mono
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    // Bytes per second and per frame, bits per sample
+    wav.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_size.to_le_bytes());
+    for s in samples {
+        wav.extend_from_slice(&((s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes());
+    }
+    wav
+}
+
+pub fn write_wav<P: AsRef<Path>>(path: P, samples: &[f32]) -> Result<(), String> {
+    let path = path.as_ref();
+    fs::write(path, wav(samples)).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{OutputConfig, OutputMode};
+    use crate::tonal_space::{AssonanceMetric, ProjectionType, TonalSpaceConfig};
+
+    #[test]
+    fn render_notes() {
+        let mut processor = Processor::new(TonalSpaceConfig::default(), ProjectionType::Meantone31KeepUnison,
+                                           AssonanceMetric::Pythagorean, OutputConfig::new(OutputMode::PerNoteChannel));
+        let input = processor.add_input(false, false);
+        let messages = vec![(input, 0.0, vec![0x90, 60, 127]), (input, 0.5, vec![0x90, 64, 64]),
+                            (input, 1.0, vec![0x80, 60, 0]), (input, 1.5, vec![0x90, 64, 0])];
+        let notes = spell_notes(&mut processor, messages);
+        assert_eq!(notes, vec![
+            RenderedNote { start: 0.0, end: 1.0, pitch: Pitch31::new("C4").unwrap(), velocity: 127 },
+            RenderedNote { start: 0.5, end: 1.5, pitch: Pitch31::new("E4").unwrap(), velocity: 64 }
+        ]);
+
+        let samples = render(&notes, 440.0);
+        assert_eq!(samples.len(), ((1.5 + RELEASE) * SAMPLE_RATE as f64).ceil() as usize);
+        assert!(samples.iter().all(|s| s.abs() <= 1.0));
+        assert!(samples[SAMPLE_RATE as usize / 4].abs() > 0.0);
+
+        let wav = wav(&samples);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(wav.len(), 44 + 2 * samples.len());
+
+        // Retuned output is played back from its keys and bends, here E4 bent 13.7 cents down on key 64
+        let mut retuned = vec![];
+        processor.handle(input, &[0x90, 64, 100], &mut retuned);
+        let messages = retuned.into_iter().map(|raw| (0.0, raw)).collect();
+        let decoded = decode_notes(messages, output::BEND_RANGE, 440.0);
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[0].pitch, Pitch31::new("E4").unwrap());
+    }
+
+    #[test]
+    fn render_out_of_order_recording() {
+        let mut processor = Processor::new(TonalSpaceConfig::default(), ProjectionType::Meantone31KeepUnison,
+                                           AssonanceMetric::Pythagorean, OutputConfig::new(OutputMode::PerNoteChannel));
+        let input = processor.add_input(false, false);
+        let recorded = |stamp, message: &[u8]| Recorded { input, stamp, message: message.to_vec() };
+        let messages = recorded_messages(vec![recorded(2_000_000, &[0x90, 60, 100]),
+                                              recorded(1_500_000, &[0x90, 64, 100]),
+                                              recorded(3_000_000, &[0x80, 60, 0]),
+                                              recorded(2_500_000, &[0x80, 64, 0])]);
+        assert_eq!(messages.iter().map(|m| m.1).collect::<Vec<_>>(), vec![0.0, 0.5, 1.0, 1.5]);
+
+        let notes = spell_notes(&mut processor, messages);
+        assert_eq!(notes.iter().map(|n| (n.start, n.end)).collect::<Vec<_>>(), vec![(0.0, 1.0), (0.5, 1.5)]);
+        assert_eq!(render(&notes, 440.0).len(), ((1.5 + RELEASE) * SAMPLE_RATE as f64).ceil() as usize);
+
+        // Notes that end before they start still fit in the mix
+        let backwards = [RenderedNote { start: 1.0, end: 0.5, pitch: Pitch31::new("A4").unwrap(), velocity: 100 }];
+        assert_eq!(render(&backwards, 440.0).len(), ((1.0 + RELEASE) * SAMPLE_RATE as f64).ceil() as usize);
+    }
+}
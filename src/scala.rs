@@ -0,0 +1,83 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::theory::Pitch31;
+
+/// Steps from C to A in 31 edo, to express pitches relative to middle C
+const C4_TO_A4: i16 = 23;
+
+/// Scala scale file of 31 equal divisions of the octave
+pub fn scl() -> String {
+    let mut scl = String::from("! 31edo.scl\n!\n31 equal divisions of the octave\n 31\n!\n");
+    for step in 1..31 {
+        scl.push_str(&format!(" {:.6}\n", Pitch31::from(step).cents_from_a4()));
+    }
+    scl.push_str(" 2/1\n");
+    scl
+}
+
+/// Scala keyboard mapping that plays `mapping` (the 31 edo pitches of the keys 60 to 71)
+/// on every octave of the keyboard, with A4 (key 69) at `reference` Hz
+pub fn kbm(mapping: &[Pitch31], reference: f64) -> String {
+    let mut degrees = mapping.iter()
+        .map(|p| p.to_steps_from_a4() + C4_TO_A4)
+        .collect::<Vec<_>>();
+
+    // Degrees must not be negative (e.g. B#3 on middle C), so shift everything up an octave.
+    // Only the reference frequency fixes the absolute pitch, so the result sounds the same.
+    if degrees.iter().any(|d| *d < 0) {
+        degrees.iter_mut().for_each(|d| *d += 31);
+    }
+
+    let names = mapping.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+    let mut kbm = format!("! 12 keys mapped to 31 edo: {}\n", names.join(" "));
+    kbm.push_str("! Map size:\n12\n");
+    kbm.push_str("! First MIDI note number to retune:\n0\n");
+    kbm.push_str("! Last MIDI note number to retune:\n127\n");
+    kbm.push_str("! Middle note where the first entry of the mapping is mapped to:\n60\n");
+    kbm.push_str("! Reference note for which frequency is given:\n69\n");
+    kbm.push_str(&format!("! Frequency to tune the above note to:\n{:.6}\n", reference));
+    kbm.push_str("! Scale degree to consider as formal octave:\n31\n");
+    kbm.push_str("! Mapping.\n");
+    for d in degrees {
+        kbm.push_str(&format!("{}\n", d));
+    }
+    kbm
+}
+
+/// Writes `base`.scl and `base`.kbm, returning their paths
+pub fn export<P: AsRef<Path>>(base: P, mapping: &[Pitch31], reference: f64) -> Result<(PathBuf, PathBuf), String> {
+    let scl_path = base.as_ref().with_extension("scl");
+    let kbm_path = base.as_ref().with_extension("kbm");
+    fs::write(&scl_path, scl()).map_err(|e| format!("{}: {}", scl_path.display(), e))?;
+    fs::write(&kbm_path, kbm(mapping, reference)).map_err(|e| format!("{}: {}", kbm_path.display(), e))?;
+    Ok((scl_path, kbm_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(names: &str) -> Vec<Pitch31> {
+        names.split(' ').map(|n| Pitch31::new(n).unwrap()).collect()
+    }
+
+    #[test]
+    fn scala_files() {
+        let scl = scl();
+        let lines = scl.lines().filter(|l| !l.starts_with('!')).collect::<Vec<_>>();
+        assert_eq!(lines[1].trim(), "31");
+        assert_eq!(lines.len(), 2 + 31);
+        assert_eq!(lines[19].trim(), "696.774194");
+
+        let plain = kbm(&mapping("C4 C#4 D4 Eb4 E4 F4 F#4 G4 Ab4 A4 Bb4 B4"), 440.0);
+        let degrees = plain.lines().rev().take(12).collect::<Vec<_>>();
+        assert_eq!(degrees.into_iter().rev().collect::<Vec<_>>(),
+                   vec!["0", "2", "5", "8", "10", "13", "15", "18", "21", "23", "26", "28"]);
+
+        // A spelling below middle C moves everything up an octave
+        let shifted = kbm(&mapping("B#3 C#4 D4 Eb4 E4 F4 F#4 G4 Ab4 A4 Bb4 B4"), 415.0);
+        assert!(shifted.contains("\n415.000000\n"));
+        assert!(shifted.ends_with("\n30\n33\n36\n39\n41\n44\n46\n49\n52\n54\n57\n59\n"));
+    }
+}
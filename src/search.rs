@@ -0,0 +1,165 @@
+use std::path::Path;
+
+use midly::number::u7;
+
+use crate::corpus::Corpus;
+use crate::musicxml;
+use crate::processor::{self, ControlInterface};
+use crate::theory::Note;
+use crate::tonal_space::{AssonanceMetric, ProjectionType, TonalSpace, TonalSpaceConfig};
+
+// Values tried by the grid search. Random search draws from the same ranges.
+const SEMITONE_THRESHOLDS: [i16; 4] = [1, 2, 3, 4];
+const DIFF_OCT_CLASH_THRESHOLDS: [u8; 4] = [0, 1, 2, 3];
+const ORDER_PRECEDENCE_COEFFICIENTS: [f64; 7] = [0.5, 0.7, 0.8, 0.9, 0.95, 0.99, 1.0];
+/// Only tried with `ProjectionType::Meantone31Neutral`, the only projection type it affects
+const NEUTRAL_BIASES: [f64; 4] = [7.0, 14.0, 21.0, 28.0];
+
+/// A piece to search parameters on: MIDI keys in the order they are played, with the note each
+/// should be spelled as. Octaves are not compared.
+pub struct Piece {
+    pub notes: Vec<(u7, Note)>
+}
+
+impl Piece {
+    /// Loads a golden corpus file (.txt, see `Corpus`) or a MusicXML score (any other extension).
+    /// The projection type and metric of a corpus file are ignored, as they are searched.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let notes = if path.extension().is_some_and(|ext| ext == "txt") {
+            Corpus::from_file(path)?.notes.into_iter().map(|(_, key, pitch, _)| (key, pitch.note)).collect()
+        } else {
+            musicxml::load(path)?.into_iter().map(|n| (n.key, n.pitch.note)).collect()
+        };
+        Ok(Piece { notes })
+    }
+}
+
+/// One combination of the parameters that are searched
+#[derive(Copy, Clone)]
+pub struct Setting {
+    pub config: TonalSpaceConfig,
+    pub metric: AssonanceMetric,
+    pub projection_type: ProjectionType
+}
+
+impl Setting {
+    /// Percentage of the notes of all `pieces` spelled as expected, each piece starting with a fresh tonal space
+    pub fn accuracy(&self, pieces: &[Piece]) -> f64 {
+        let control = ControlInterface::default();
+        let (mut total, mut correct) = (0, 0);
+
+        for piece in pieces {
+            let mut tonal_space = TonalSpace::new(self.config);
+            for &(key, expected) in &piece.notes {
+                let pitch = processor::convert_to_31(key, &mut tonal_space, self.metric, self.projection_type, &control);
+                total += 1;
+                if pitch.note == expected {
+                    correct += 1;
+                }
+            }
+        }
+
+        if total == 0 {
+            return 100.0;
+        }
+        correct as f64 * 100.0 / total as f64
+    }
+}
+
+/// Every combination of the grid values, for every metric and projection type
+pub fn grid() -> Vec<Setting> {
+    let mut settings = vec![];
+    for projection_type in ProjectionType::all() {
+        let biases = match projection_type {
+            ProjectionType::Meantone31Neutral => &NEUTRAL_BIASES[..],
+            _ => &[TonalSpaceConfig::default().neutral_bias][..]
+        };
+        for metric in AssonanceMetric::ALL {
+            for &semitone_threshold in &SEMITONE_THRESHOLDS {
+                for &diff_oct_clash_threshold in &DIFF_OCT_CLASH_THRESHOLDS {
+                    for &order_precedence_coefficient in &ORDER_PRECEDENCE_COEFFICIENTS {
+                        for &neutral_bias in biases {
+                            let config = TonalSpaceConfig {
+                                semitone_threshold, diff_oct_clash_threshold, order_precedence_coefficient, neutral_bias
+                            };
+                            settings.push(Setting { config, metric, projection_type });
+                        }
+                    }
+                }
+            }
+        }
+    }
+    settings
+}
+
+/// `count` settings drawn uniformly from the ranges of the grid, reproducibly for the same `seed`
+pub fn random(count: usize, seed: u64) -> Vec<Setting> {
+    let mut rng = Rng(seed.max(1));
+    let projection_types = ProjectionType::all();
+
+    (0..count).map(|_| {
+        let config = TonalSpaceConfig {
+            semitone_threshold: *rng.pick(&SEMITONE_THRESHOLDS),
+            diff_oct_clash_threshold: *rng.pick(&DIFF_OCT_CLASH_THRESHOLDS),
+            order_precedence_coefficient: rng.between(ORDER_PRECEDENCE_COEFFICIENTS[0], 1.0),
+            neutral_bias: rng.between(NEUTRAL_BIASES[0], NEUTRAL_BIASES[NEUTRAL_BIASES.len() - 1])
+        };
+        Setting { config, metric: *rng.pick(&AssonanceMetric::ALL), projection_type: *rng.pick(&projection_types) }
+    }).collect()
+}
+
+/// Evaluates every setting on `pieces`, returning them with their accuracy, best first.
+/// Settings with equal accuracy keep their order.
+pub fn search(settings: Vec<Setting>, pieces: &[Piece]) -> Vec<(Setting, f64)> {
+    let mut results = settings.into_iter()
+        .map(|s| (s, s.accuracy(pieces)))
+        .collect::<Vec<_>>();
+    results.sort_by(|a, b| b.1.total_cmp(&a.1));
+    results
+}
+
+/// Small xorshift generator, so that random search needs no dependency
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn between(&mut self, low: f64, high: f64) -> f64 {
+        low + (self.next() >> 11) as f64 / (1u64 << 53) as f64 * (high - low)
+    }
+
+    fn pick<'a, T>(&mut self, values: &'a [T]) -> &'a T {
+        &values[(self.next() % values.len() as u64) as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_finds_best_setting() {
+        // C E G, then Eb G Bb: the default spells the Eb chord wrongly
+        let notes = [(60, Note::C), (64, Note::E), (67, Note::G), (63, Note::Eb), (67, Note::G), (70, Note::Bb)];
+        let pieces = [Piece { notes: notes.iter().map(|&(k, n)| (u7::from(k), n)).collect() }];
+
+        let default = Setting {
+            config: TonalSpaceConfig::default(),
+            metric: AssonanceMetric::Pythagorean,
+            projection_type: ProjectionType::Meantone31KeepUnison
+        };
+        assert!(default.accuracy(&pieces) < 100.0);
+        let results = search(grid(), &pieces);
+        // The best setting spells every note, including Eb G Bb, as written
+        assert_eq!(results[0].1, 100.0);
+        assert!(results.windows(2).all(|w| w[0].1 >= w[1].1));
+
+        assert_eq!(random(10, 7).len(), 10);
+    }
+}
@@ -1,7 +1,11 @@
 use regex::Regex;
 use lazy_static::lazy_static;
 use std::str::FromStr;
-use std::ops::{Add, RemAssign};
+use std::fmt;
+use std::ops::Add;
+
+#[cfg(feature = "serde")]
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
 macro_rules! patent_val {
     ($edo:expr=>edo, [$($harm:expr),+]) => {
@@ -18,7 +22,41 @@ lazy_static! {
     static ref PATENT_VAL31: Vec<f64> = patent_val!(31=>edo, [2, 3, 5, 7, 11, 13, 17]);
 }
 
-#[derive(Copy, Clone, Eq, PartialEq, Hash)]
+/// Frequency of A4 in Hz that 12 edo instruments are tuned to
+pub const STANDARD_REFERENCE: f64 = 440.0;
+
+/// Named reference pitches for A4, in Hz
+pub const REFERENCES: [(&str, f64); 3] = [("modern", STANDARD_REFERENCE), ("verdi", 432.0), ("baroque", 415.0)];
+
+/// Size of a 31 edo step in cents
+pub const STEP_CENTS: f64 = 1200.0 / 31.0;
+
+/// Parses a reference pitch for A4, given in Hz or as one of the names in `REFERENCES`
+pub fn reference_from_name(name: &str) -> Result<f64, String> {
+    let hz = match REFERENCES.iter().find(|(n, _)| *n == name) {
+        Some((_, hz)) => *hz,
+        None => name.parse::<f64>().map_err(|_| format!("invalid reference pitch: {}", name))?
+    };
+    if hz.is_finite() && hz > 0.0 {
+        Ok(hz)
+    } else {
+        Err(format!("invalid reference pitch: {}", name))
+    }
+}
+
+/// How many cents A4 at `reference` Hz is above A4 at `STANDARD_REFERENCE`
+pub fn reference_cents(reference: f64) -> f64 {
+    1200.0 * (reference / STANDARD_REFERENCE).log2()
+}
+
+/// The 31 edo pitch nearest to `frequency` Hz, with A4 at `reference` Hz.
+/// Each step is spelled the way `Pitch31::from` spells it.
+pub fn nearest_pitch31(frequency: f64, reference: f64) -> Pitch31 {
+    let steps = (31.0 * (frequency / reference).log2()).round();
+    Pitch31::from(steps as i16)
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 pub struct Pitch31 {
     pub note: Note,
     pub octave: i16,
@@ -35,7 +73,13 @@ impl Pitch31 {
                 match Note::new(note_str.as_str()) {
                     Ok(note) => {
                         if let Ok(octave) = i16::from_str(oct.as_str()) {
-                            Ok(Pitch31{note, octave})
+                            // The octave number belongs to the letter name, which matters for
+                            // spellings that cross C, e.g. Cb5 is a diesis above B4.
+                            let letter = Note::new(&note_str.as_str()[..1]).unwrap();
+                            let accidental = (note.to_steps_from_a() - letter.to_steps_from_a() + 15)
+                                .rem_euclid(31) - 15;
+                            let steps = letter.to_steps_from_a() + accidental + 31 * (octave - 4);
+                            Ok(Pitch31::from(steps))
                         } else {
                             Err("invalid octave: ".to_owned() + oct.as_str())
                         }
@@ -47,14 +91,52 @@ impl Pitch31 {
         }
     }
 
-    pub fn to_steps_from_a4(&self) -> i16 {
+    pub fn to_steps_from_a4(self) -> i16 {
         self.note.to_steps_from_a() + 31 * (self.octave - 4)
     }
+
+    pub fn cents_from_a4(self) -> f64 {
+        self.to_steps_from_a4() as f64 * STEP_CENTS
+    }
+
+    /// Frequency in Hz, with A4 at `reference` Hz
+    pub fn frequency(self, reference: f64) -> f64 {
+        reference * 2f64.powf(self.cents_from_a4() / 1200.0)
+    }
+
+    /// How many cents the pitch is above the 12 edo MIDI `key`, with both tunings sharing A4
+    pub fn cents_offset_from_12edo(self, key: u8) -> f64 {
+        self.cents_from_a4() - (key as f64 - 69.0) * 100.0
+    }
+}
+
+impl fmt::Display for Pitch31 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Cb is written in the octave of the C above it, see Note::BuCb
+        let octave = if self.note == Note::BuCb { self.octave + 1 } else { self.octave };
+        write!(f, "{}{}", self.note, octave)
+    }
+}
+
+/// Written as its name, e.g. "Cb5"
+#[cfg(feature = "serde")]
+impl Serialize for Pitch31 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Pitch31 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Pitch31::new(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
 }
 
 impl From<i16> for Pitch31 {
     fn from(steps_from_a4: i16) -> Self {
-        let octave = steps_from_a4.div_euclid(31) + 4;
+        // Octaves begin at C, which is 23 steps below A
+        let octave = (steps_from_a4 + 23).div_euclid(31) + 4;
         let note = Note::from(steps_from_a4.rem_euclid(31));
 
         Pitch31 {
@@ -71,7 +153,7 @@ impl Add<i16> for Pitch31 {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub enum Note {
     Abb, Ebb, Bbb,
     // Cb is renamed BuCb to prevent confusion of the octaves
@@ -89,8 +171,9 @@ impl Note {
         let capts = NOTE_REGEX.captures(s);
         match capts {
             Some(capts) => {
-                let note = capts.name("note").unwrap().as_str().to_lowercase().as_str();
-                let acc = capts.name("acc");
+                let note = capts.name("note").unwrap().as_str().to_uppercase();
+                let note = note.as_str();
+                let acc = capts.name("acc").filter(|acc| !acc.as_str().is_empty());
 
                 match acc {
                     None => {
@@ -220,6 +303,24 @@ impl Note {
         }
     }
 
+    /// Returns the position of this note on the chain of fifths, counted in fifths from D
+    /// (the centre of the naturals F C G D A E B), from -15 (Gbb) to 15 (Ax)
+    pub fn chain_position(self) -> i16 {
+        // 19 is the inverse of a fifth (18 steps) modulo 31
+        let fifths = ((self.to_steps_from_a() - Note::D.to_steps_from_a()) * 19).rem_euclid(31);
+        if fifths > 15 {
+            fifths - 31
+        } else {
+            fifths
+        }
+    }
+
+    /// Returns the number of sharps or flats needed to spell this note
+    /// e.g. 0 for F, 1 for Bb, 2 for Cx
+    pub fn accidentals(self) -> u8 {
+        ((self.chain_position().abs() + 3) / 7) as u8
+    }
+
     /// Returns the smallest number of fifths it takes to traverse the current note
     /// to the `other` note
     pub fn fifths_to(self, other: Note) -> u8 {
@@ -244,6 +345,37 @@ impl Note {
     }
 }
 
+impl fmt::Display for Note {
+    /// Writes the note name using # and b accidentals. Notes with two names are written
+    /// with the name that stays in the same octave as the enum's octave numbering
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Note::*;
+        let name = match self {
+            Abb => "Abb", Ebb => "Ebb", Bbb => "Bbb",
+            Fb => "Fb", BuCb => "Cb", Gb => "Gb", Db => "Db", Ab => "Ab", Eb => "Eb", Bb => "Bb",
+            F => "F", C => "C", G => "G", D => "D", A => "A", E => "E", B => "B",
+            Fs => "F#", Cs => "C#", Gs => "G#", Ds => "D#", As => "A#", Es => "E#", Bs => "B#",
+            Fx => "Fx", Cx => "Cx", Gx => "Gx", DxFbb => "Dx", AxCbb => "Ax", ExGbb => "Ex", BxDbb => "Dbb",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Written as its name, e.g. "F#"
+#[cfg(feature = "serde")]
+impl Serialize for Note {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Note {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Note::new(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
 impl From<i16> for Note {
     /// Converts a number representing the number of steps from the note A
     /// into the Note enum value.
@@ -251,7 +383,7 @@ impl From<i16> for Note {
         use Note::*;
         let mut steps_from_a = steps_from_a;
         if steps_from_a < 0 {
-            steps_from_a = 31 + steps_from_a;
+            steps_from_a += 31;
         }
         steps_from_a = ((steps_from_a + 23) % 31) - 23;
         match steps_from_a {
@@ -273,4 +405,118 @@ impl Add<i16> for Note {
     fn add(self, rhs: i16) -> Self::Output {
         Note::from(self.to_steps_from_a() + rhs)
     }
-}
\ No newline at end of file
+}
+
+/// Twelve consecutive notes of the chain of fifths, one for every 12 edo pitch class (e.g. Eb to G#).
+/// Spells every key the same way regardless of harmonic context.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FifthsChain {
+    /// Note of each pitch class, starting from C
+    notes: [Note; 12]
+}
+
+impl FifthsChain {
+    /// Parses the first and last note of the chain, e.g. "Eb:G#", or just the first note, e.g. "Eb"
+    pub fn new(s: &str) -> Result<Self, String> {
+        let mut split = s.splitn(2, ':');
+        let first = Note::new(split.next().unwrap_or("").trim())?;
+        if let Some(last) = split.next() {
+            let last = Note::new(last.trim())?;
+            if last.chain_position() - first.chain_position() != 11 {
+                return Err(format!("{} to {} is not a chain of 12 fifths", first, last));
+            }
+        }
+        if first.chain_position() + 11 > 15 {
+            return Err(format!("a chain of 12 fifths starting on {} goes beyond double sharps", first));
+        }
+
+        let mut notes = [Note::C; 12];
+        for i in 0..12 {
+            // A fifth is 18 steps, and 7 semitones in 12 edo. D is pitch class 2.
+            let note = first + 18 * i;
+            notes[(2 + 7 * note.chain_position()).rem_euclid(12) as usize] = note;
+        }
+        Ok(FifthsChain { notes })
+    }
+
+    /// Spells `key` with the chain's note for its pitch class, in the octave closest to the key
+    pub fn spell(&self, key: u8) -> Pitch31 {
+        let steps = self.notes[key as usize % 12].to_steps_from_a();
+        let target = (key as f64 - 69.0) * 31.0 / 12.0;
+        let octaves = ((target - steps as f64) / 31.0).round() as i16;
+        Pitch31::from(steps + 31 * octaves)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chain_position_and_accidentals() {
+        assert_eq!(Note::D.chain_position(), 0);
+        assert_eq!(Note::F.chain_position(), -3);
+        assert_eq!(Note::B.chain_position(), 3);
+        assert_eq!(Note::Fs.chain_position(), 4);
+        assert_eq!(Note::AxCbb.chain_position(), 15);
+        assert_eq!(Note::ExGbb.chain_position(), -15);
+
+        assert_eq!(Note::C.accidentals(), 0);
+        assert_eq!(Note::Bb.accidentals(), 1);
+        assert_eq!(Note::Bs.accidentals(), 1);
+        assert_eq!(Note::Fb.accidentals(), 1);
+        assert_eq!(Note::Cx.accidentals(), 2);
+        assert_eq!(Note::Bbb.accidentals(), 2);
+    }
+
+    #[test]
+    fn pitch_names_round_trip() {
+        assert_eq!(Pitch31::new("A4").unwrap().to_steps_from_a4(), 0);
+        assert_eq!(Pitch31::new("C4").unwrap().to_steps_from_a4(), -23);
+        assert_eq!(Pitch31::new("Cb5").unwrap().to_steps_from_a4(), 6);
+        assert_eq!(Pitch31::new("B#4").unwrap().to_steps_from_a4(), 7);
+        assert_eq!(Pitch31::new("Bx4").unwrap().to_steps_from_a4(), 9);
+        assert_eq!(Pitch31::new("Dbb4").unwrap().to_steps_from_a4(), -22);
+
+        for steps in -40..40 {
+            let pitch = Pitch31::from(steps);
+            assert_eq!(Pitch31::new(&pitch.to_string()).unwrap(), pitch);
+        }
+    }
+
+    #[test]
+    fn frequencies_and_cents() {
+        let pitch = |name| Pitch31::new(name).unwrap();
+        assert_eq!(pitch("A4").frequency(STANDARD_REFERENCE), 440.0);
+        assert_eq!(pitch("A5").frequency(415.0), 830.0);
+        assert!((pitch("C4").frequency(440.0) - 440.0 * 2f64.powf(-23.0 / 31.0)).abs() < 1e-9);
+
+        assert!((pitch("E4").cents_from_a4() - -13.0 * 1200.0 / 31.0).abs() < 1e-9);
+        assert!((pitch("C4").cents_offset_from_12edo(60) - 9.677).abs() < 1e-3);
+        assert!((pitch("B#4").cents_offset_from_12edo(72) - -29.032).abs() < 1e-3);
+
+        for steps in -60..60 {
+            let pitch = Pitch31::from(steps);
+            assert_eq!(nearest_pitch31(pitch.frequency(432.0) * 1.005, 432.0), pitch);
+        }
+
+        assert_eq!(reference_from_name("baroque"), Ok(415.0));
+        assert_eq!(reference_from_name("442.5"), Ok(442.5));
+        assert!(reference_from_name("-440").is_err());
+        assert!((reference_cents(220.0) - -1200.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn fifths_chain() {
+        let chain = FifthsChain::new("Eb:G#").unwrap();
+        let names = (60..72).map(|k| chain.spell(k).to_string()).collect::<Vec<_>>();
+        assert_eq!(names.join(" "), "C4 C#4 D4 Eb4 E4 F4 F#4 G4 G#4 A4 Bb4 B4");
+
+        let chain = FifthsChain::new("Fb").unwrap();
+        assert_eq!(chain.spell(71).to_string(), "Cb5");
+        assert_eq!(chain.spell(64).to_string(), "Fb4");
+
+        assert!(FifthsChain::new("Eb:A").is_err());
+        assert!(FifthsChain::new("G#").is_err());
+    }
+}
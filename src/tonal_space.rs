@@ -1,32 +1,201 @@
 use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
 use crate::theory::{Note, Pitch31};
+use crate::projection;
 use midly::number::u7;
 
-/// An interval that is this number of steps or less apart will be regarded as a "semitone" clash
-const SEMITONE_THRESHOLD: i16 = 3;
+#[cfg(feature = "serde")]
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::convert::TryFrom;
 
-/// Once TSPitch.clash_counter exceeds this number it will destroy itself
-/// Usage: If you believe a major 7th/min 9th has equal precedence in the step-wise shift of a note in
-///        the tonal space as a min 2nd, set this to zero.
-///
-///        If you think octave reduction is an invalid concept, set this value to
-///        (x - 1) where x is the coefficient multiplier of how much more important the
-///        < min2nd interval is as compared to octave-displaced inversions.
-const DIFF_OCT_CLASH_THRESHOLD: u8 = 0;
+/// Tunable parameters of the tonal space heuristic
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(try_from = "TonalSpaceConfigFields"))]
+pub struct TonalSpaceConfig {
+    /// An interval that is this number of steps or less apart will be regarded as a "semitone" clash
+    pub semitone_threshold: i16,
+
+    /// Once TSPitch.clash_counter exceeds this number it will destroy itself
+    /// Usage: If you believe a major 7th/min 9th has equal precedence in the step-wise shift of a note in
+    ///        the tonal space as a min 2nd, set this to zero.
+    ///
+    ///        If you think octave reduction is an invalid concept, set this value to
+    ///        (x - 1) where x is the coefficient multiplier of how much more important the
+    ///        < min2nd interval is as compared to octave-displaced inversions.
+    pub diff_oct_clash_threshold: u8,
+
+    /// The second most recent note is this much as important as the first,
+    /// the third most recent note is this much as important as the second,
+    /// etc...
+    pub order_precedence_coefficient: f64,
+
+    /// Amount subtracted from the assonance score of a candidate for every note in the tonal space
+    /// it forms a neutral interval with. Only applies to `ProjectionType::Meantone31Neutral`.
+    ///
+    /// A neutral third is 15 fifths away from its root and has a prior of 2, while a major third
+    /// is 4 fifths away, so the bias needs to be more than 13 for a neutral interval against a
+    /// single note to win.
+    pub neutral_bias: f64
+}
+
+impl Default for TonalSpaceConfig {
+    fn default() -> Self {
+        TonalSpaceConfig {
+            semitone_threshold: 3,
+            diff_oct_clash_threshold: 0,
+            order_precedence_coefficient: 0.99,
+            neutral_bias: 14.0
+        }
+    }
+}
+
+impl TonalSpaceConfig {
+    /// Loads a config from a TOML file. Parameters not present in the file keep their default value.
+    ///
+    /// ```toml
+    /// semitone_threshold = 3
+    /// order_precedence_coefficient = 0.95
+    /// ```
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::from_toml(&source)
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
 
-/// The second most recent note is this much as important as the first,
-/// the third most recent note is this much as important as the second,
-/// etc...
-const ORDER_PRECEDENCE_COEFFICIENT: f64 = 0.99;
+    pub fn from_toml(source: &str) -> Result<Self, String> {
+        let root = source.parse::<toml::Value>().map_err(|e| e.to_string())?;
+        let root = root.as_table().ok_or("expected a table of parameters")?;
 
-#[derive(Default)]
+        let mut config = TonalSpaceConfig::default();
+        for (name, value) in root {
+            // Reuse the parsing of `set` so that files and the command line accept the same values
+            let value = match value {
+                toml::Value::Integer(i) => i.to_string(),
+                toml::Value::Float(f) => f.to_string(),
+                v => return Err(format!("{} must be a number, found {}", name, v.type_str()))
+            };
+            config.set(name, &value)?;
+        }
+        Ok(config)
+    }
+
+    /// Writes the config in the format read by `from_toml`
+    pub fn to_toml(self) -> String {
+        format!("semitone_threshold = {}\ndiff_oct_clash_threshold = {}\norder_precedence_coefficient = {:?}\n\
+                 neutral_bias = {:?}\n",
+                self.semitone_threshold, self.diff_oct_clash_threshold, self.order_precedence_coefficient,
+                self.neutral_bias)
+    }
+
+    /// Sets the parameter called `name` (the name of the field) to `value`. The config is left as it was
+    /// if the value is rejected.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        fn parse<T: FromStr>(name: &str, value: &str) -> Result<T, String> {
+            value.parse::<T>().map_err(|_| format!("invalid value for {}: {}", name, value))
+        }
+
+        let mut config = *self;
+        match name {
+            "semitone_threshold" => config.semitone_threshold = parse(name, value)?,
+            "diff_oct_clash_threshold" => config.diff_oct_clash_threshold = parse(name, value)?,
+            "order_precedence_coefficient" => config.order_precedence_coefficient = parse(name, value)?,
+            "neutral_bias" => config.neutral_bias = parse(name, value)?,
+            _ => return Err(format!("unknown tonal space parameter: {}", name))
+        }
+        config.check()?;
+        *self = config;
+        Ok(())
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.semitone_threshold < 0 || self.semitone_threshold > 15 {
+            return Err(format!("semitone_threshold must be between 0 and 15, found {}", self.semitone_threshold));
+        }
+        if !self.order_precedence_coefficient.is_finite() || self.order_precedence_coefficient < 0.0 {
+            return Err(format!("order_precedence_coefficient must be a positive number, found {}",
+                               self.order_precedence_coefficient));
+        }
+        if !self.neutral_bias.is_finite() {
+            return Err(format!("neutral_bias must be finite, found {}", self.neutral_bias));
+        }
+        Ok(())
+    }
+}
+
+/// `TonalSpaceConfig` before its parameters are checked, for serde. Missing parameters keep their default value.
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TonalSpaceConfigFields {
+    semitone_threshold: i16,
+    diff_oct_clash_threshold: u8,
+    order_precedence_coefficient: f64,
+    neutral_bias: f64
+}
+
+#[cfg(feature = "serde")]
+impl Default for TonalSpaceConfigFields {
+    fn default() -> Self {
+        let c = TonalSpaceConfig::default();
+        TonalSpaceConfigFields {
+            semitone_threshold: c.semitone_threshold,
+            diff_oct_clash_threshold: c.diff_oct_clash_threshold,
+            order_precedence_coefficient: c.order_precedence_coefficient,
+            neutral_bias: c.neutral_bias
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<TonalSpaceConfigFields> for TonalSpaceConfig {
+    type Error = String;
+
+    fn try_from(c: TonalSpaceConfigFields) -> Result<Self, String> {
+        let config = TonalSpaceConfig {
+            semitone_threshold: c.semitone_threshold,
+            diff_oct_clash_threshold: c.diff_oct_clash_threshold,
+            order_precedence_coefficient: c.order_precedence_coefficient,
+            neutral_bias: c.neutral_bias
+        };
+        config.check()?;
+        Ok(config)
+    }
+}
+
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(try_from = "TonalSpaceFields"))]
 pub struct TonalSpace {
     notes: HashMap<Note, Vec<TSPitch>>,
-    note_order: Vec<Note>
+    note_order: Vec<Note>,
+    config: TonalSpaceConfig
+}
+
+/// `TonalSpace` before it is checked, for serde
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct TonalSpaceFields {
+    notes: HashMap<Note, Vec<TSPitch>>,
+    note_order: Vec<Note>,
+    config: TonalSpaceConfig
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<TonalSpaceFields> for TonalSpace {
+    type Error = String;
+
+    fn try_from(ts: TonalSpaceFields) -> Result<Self, String> {
+        let ts = TonalSpace { notes: ts.notes, note_order: ts.note_order, config: ts.config };
+        ts.check()?;
+        Ok(ts)
+    }
 }
 
 impl TonalSpace {
-    pub fn new() -> Self {
+    pub fn new(config: TonalSpaceConfig) -> Self {
         let mut notes = HashMap::new();
         notes.insert(Note::C,
                      vec![TSPitch::new(
@@ -35,8 +204,91 @@ impl TonalSpace {
 
         TonalSpace {
             notes,
-            note_order: vec![Note::C]
+            note_order: vec![Note::C],
+            config
+        }
+    }
+
+    /// Writes the notes of the tonal space, with their order and clash counters, as TOML.
+    /// The config is left out, so that a saved state can be used with any config.
+    ///
+    /// ```toml
+    /// note_order = ["G", "E", "C"]
+    ///
+    /// [[pitches]]
+    /// pitch = "G4"
+    /// midi_key = 67
+    /// clash_counter = 0
+    /// ```
+    pub fn to_toml(&self) -> String {
+        let order = self.note_order.iter().map(|n| format!("\"{}\"", n)).collect::<Vec<_>>();
+        let mut toml = format!("note_order = [{}]\n", order.join(", "));
+        for note in &self.note_order {
+            for p in self.notes.get(note).into_iter().flatten() {
+                toml.push_str(&format!("\n[[pitches]]\npitch = \"{}\"\nmidi_key = {}\nclash_counter = {}\n",
+                                       p.pitch, p.midi_key.as_int(), p.clash_counter));
+            }
+        }
+        toml
+    }
+
+    /// Reads a tonal space written by `to_toml`, to be used with `config`
+    pub fn from_toml(source: &str, config: TonalSpaceConfig) -> Result<Self, String> {
+        let root = source.parse::<toml::Value>().map_err(|e| e.to_string())?;
+        let mut ts = TonalSpace { notes: HashMap::new(), note_order: vec![], config };
+
+        let order = root.get("note_order").and_then(|o| o.as_array()).ok_or("expected a note_order array")?;
+        for name in order {
+            let note = Note::new(name.as_str().ok_or("note_order must contain note names")?)?;
+            ts.notes.insert(note, vec![]);
+            ts.note_order.push(note);
+        }
+
+        let empty = vec![];
+        let pitches = match root.get("pitches") {
+            Some(pitches) => pitches.as_array().ok_or("pitches must be an array of tables")?,
+            None => &empty
+        };
+        for p in pitches {
+            let field = |name: &str| p.get(name).ok_or_else(|| format!("pitch without {}", name));
+            let pitch = Pitch31::new(field("pitch")?.as_str().ok_or("pitch must be a pitch name")?)?;
+            let midi_key = field("midi_key")?.as_integer()
+                .and_then(|k| u8::try_from(k).ok())
+                .and_then(u7::try_from)
+                .ok_or("midi_key must be a MIDI key")?;
+            let clash_counter = field("clash_counter")?.as_integer()
+                .and_then(|c| u8::try_from(c).ok())
+                .ok_or("clash_counter must be between 0 and 255")?;
+
+            ts.notes.get_mut(&pitch.note)
+                .ok_or_else(|| format!("{} is not in note_order", pitch.note))?
+                .push(TSPitch { pitch, clash_counter, midi_key });
         }
+        ts.check()?;
+        Ok(ts)
+    }
+
+    /// Checks that a restored state is one `insert` could have made: every note is listed once in
+    /// note_order, and pitches are filed under their note. Spelling also needs at least one pitch to
+    /// project from, which a tonal space always has once it was created with `new`.
+    fn check(&self) -> Result<(), String> {
+        for (i, note) in self.note_order.iter().enumerate() {
+            if self.note_order[..i].contains(note) {
+                return Err(format!("{} appears twice in note_order", note));
+            }
+        }
+        for (note, pitches) in &self.notes {
+            if !self.note_order.contains(note) {
+                return Err(format!("{} is not in note_order", note));
+            }
+            if let Some(p) = pitches.iter().find(|p| p.pitch.note != *note) {
+                return Err(format!("{} is listed under {}", p.pitch, note));
+            }
+        }
+        if self.notes.values().all(Vec::is_empty) {
+            return Err("a tonal space needs at least one pitch".to_owned());
+        }
+        Ok(())
     }
 
     pub fn insert(&mut self, pitch: Pitch31, midi_key: u7) {
@@ -62,18 +314,21 @@ impl TonalSpace {
 
         // Loop through candidate notes that are considered adjacent and 'clashing' w.r.t. the
         // newly-added pitch. Remove them if they are present in the tonal space.
-        for dieses in -SEMITONE_THRESHOLD..=SEMITONE_THRESHOLD {
+        let threshold = self.config.semitone_threshold;
+        let diff_oct_clash_threshold = self.config.diff_oct_clash_threshold;
+        for dieses in -threshold..=threshold {
             if dieses == 0 {
                 continue
             }
 
             let n = Note::from(pitch.note.to_steps_from_a() + dieses);
             if let Some(adj_pitch_octaves) = self.notes.get_mut(&n) {
-                adj_pitch_octaves.retain(|adj_pitch| {
-                    if (adj_pitch.pitch.to_steps_from_a4() - pitch.to_steps_from_a4()).abs() > SEMITONE_THRESHOLD {
-                        // Not same octave, use DIFF_OCT_CLASH_THRESHOLD to determine if the
-                        // adjacent note should be kept as part of the tonal space
-                        if adj_pitch.clash_counter != DIFF_OCT_CLASH_THRESHOLD {
+                adj_pitch_octaves.retain_mut(|adj_pitch| {
+                    if (adj_pitch.pitch.to_steps_from_a4() - pitch.to_steps_from_a4()).abs() > threshold {
+                        // Not same octave, count the clash and use diff_oct_clash_threshold to
+                        // determine if the adjacent note should be kept as part of the tonal space
+                        adj_pitch.clash_counter = adj_pitch.clash_counter.saturating_add(1);
+                        if adj_pitch.clash_counter <= diff_oct_clash_threshold {
                             return true
                         }
                     }
@@ -91,45 +346,83 @@ impl TonalSpace {
         self.note_order.insert(0, pitch.note);
     }
 
-    /// Returns all possible note candidates sorted by best (lowest) assonance score first.
+    /// Returns all possible note candidates sorted by best (lowest) score first.
+    ///
+    /// The score of a candidate is the sum of its assonance coefficient and prior cost with respect
+    /// to every note in the tonal space, weighted by how recently that note was played.
     pub fn convert_to_31(&self, midi_note: u7, am: AssonanceMetric, pt: ProjectionType) -> Vec<(Pitch31, f64)> {
-        let mut scores = HashMap::new();
+        // Maps candidates to (score, prior)
+        let mut scores: HashMap<Pitch31, (f64, f64)> = HashMap::new();
 
         let mut order_multiplier = 1.0;
 
         for n in &self.note_order {
-            if let Some(pitches) = self.notes.get(&n) {
+            if let Some(pitches) = self.notes.get(n) {
                 for ts_pitch in pitches {
                     let candidates = ts_pitch.get_candidate_projections(midi_note, pt);
-                    for can in candidates {
+                    for (can, prior) in candidates {
+                        let mut coefficient = ts_pitch.get_assonance_coefficient(can, am) + prior;
+
+                        if let ProjectionType::Meantone31Neutral = pt {
+                            if is_neutral_interval(can.to_steps_from_a4() - ts_pitch.pitch.to_steps_from_a4()) {
+                                coefficient -= self.config.neutral_bias;
+                            }
+                        }
+
                         // divis by number of pitches in the same octave necessary to prevent double counting
                         // octaves (TODO: or is it?)
-                        let assonance =
-                            ts_pitch.get_assonance_coefficient(can, am)
-                                / pitches.len() as f64
-                                * order_multiplier;
-
-                        if let Some( score) = scores.get_mut(&can) {
-                            *score += assonance;
-                        } else {
-                            scores.insert(can, assonance);
-                        }
+                        let weight = order_multiplier / pitches.len() as f64;
+
+                        let entry = scores.entry(can).or_insert((0.0, 0.0));
+                        entry.0 += coefficient * weight;
+                        entry.1 += prior * weight;
                     }
                 }
             }
-            order_multiplier *= ORDER_PRECEDENCE_COEFFICIENT;
+            order_multiplier *= self.config.order_precedence_coefficient;
         }
 
-        let mut sorted = scores
-            .iter()
-            .map(|(p, s)| (*p, *s))
-            .collect::<Vec<_>>();
-        sorted.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
-        sorted
+        let mut sorted = scores.into_iter().collect::<Vec<_>>();
+
+        // Ties are broken by lowest prior, then smallest deviation from 12 edo, then fewest
+        // accidentals, then lowest pitch, so that the result never depends on HashMap order.
+        sorted.sort_by_key(|(p, (score, prior))| (
+            quantize(*score),
+            quantize(*prior),
+            quantize(deviation_from_12edo(*p, midi_note)),
+            p.note.accidentals(),
+            p.to_steps_from_a4()
+        ));
+
+        sorted.into_iter().map(|(p, (score, _))| (p, score)).collect()
+    }
+
+    /// The pitches in the tonal space with the weight they are scored with, which is 1 for the most
+    /// recently played note and `order_precedence_coefficient` times less for each older one
+    pub fn members(&self) -> Vec<(Pitch31, f64)> {
+        let mut weight = 1.0;
+        let mut members = vec![];
+        for n in &self.note_order {
+            for ts_pitch in self.notes.get(n).into_iter().flatten() {
+                members.push((ts_pitch.pitch, weight));
+            }
+            weight *= self.config.order_precedence_coefficient;
+        }
+        members
+    }
+
+    /// The spelling each key of the octave from middle C (60 to 71) would get if it was played now
+    pub fn mapping(&self, am: AssonanceMetric, pt: ProjectionType) -> Vec<Pitch31> {
+        (60..72).map(|key| {
+            let key = u7::from(key);
+            self.convert_to_31(key, am, pt).first().map_or_else(|| pt.static_spelling(key), |c| c.0)
+        }).collect()
     }
 
 }
 
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(try_from = "TSPitchFields", into = "TSPitchFields"))]
 pub struct TSPitch {
     // Abstraction for values of tonal space hashmap, for future use of assigning
     // additional properties to each note in the tonal space
@@ -144,6 +437,32 @@ pub struct TSPitch {
     midi_key: u7
 }
 
+/// `TSPitch` with the MIDI key as a plain number, for serde
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct TSPitchFields {
+    pitch: Pitch31,
+    clash_counter: u8,
+    midi_key: u8
+}
+
+#[cfg(feature = "serde")]
+impl From<TSPitch> for TSPitchFields {
+    fn from(p: TSPitch) -> Self {
+        TSPitchFields { pitch: p.pitch, clash_counter: p.clash_counter, midi_key: p.midi_key.as_int() }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<TSPitchFields> for TSPitch {
+    type Error = String;
+
+    fn try_from(p: TSPitchFields) -> Result<Self, String> {
+        let midi_key = u7::try_from(p.midi_key).ok_or_else(|| format!("invalid MIDI key: {}", p.midi_key))?;
+        Ok(TSPitch { pitch: p.pitch, clash_counter: p.clash_counter, midi_key })
+    }
+}
+
 impl TSPitch {
     pub fn new(pitch: Pitch31, midi_key: u7) -> Self {
         TSPitch {
@@ -164,60 +483,77 @@ impl TSPitch {
     }
 
     /// Get possible 31 edo realisations of the 12 edo interval created between
-    /// `self.midi_key` and `midi_note`
-    pub fn get_candidate_projections(&self, midi_note: u7, projection_type: ProjectionType) -> Vec<Pitch31> {
+    /// `self.midi_key` and `midi_note`, along with the prior cost of each realisation
+    pub fn get_candidate_projections(&self, midi_note: u7, projection_type: ProjectionType) -> Vec<(Pitch31, f64)> {
         let dist12 = (midi_note.as_int() as i16) - (self.midi_key.as_int() as i16);
         let dist12_octs = dist12.div_euclid(12);
         let dist12_semis = dist12.rem_euclid(12);
 
-        match projection_type {
-            ProjectionType::Meantone17 => {
-                let candidate = match dist12_semis {
-                    0 => vec![0],
-                    1 => vec![2, 3],
-                    2 => vec![5],
-                    3 => vec![7, 8],
-                    4 => vec![10],
-                    5 => vec![13],
-                    6 => vec![15, 16],
-                    7 => vec![18],
-                    8 => vec![20, 21],
-                    9 => vec![23],
-                    10 => vec![25, 26],
-                    11 => vec![28],
-                    _ => panic!("impossible scenario")
-                };
-
-                candidate.into_iter().map(|x| self.pitch + (x + 31 * dist12_octs)).collect()
-            }
-            ProjectionType::Meantone31KeepUnison => {
-                let candidate = match dist12_semis {
-                    0 => vec![0],
-                    1 => vec![2, 3],
-                    2 => vec![5, 4, 6],
-                    3 => vec![7, 8],
-                    4 => vec![10, 9, 11],
-                    5 => vec![13, 12, 14],
-                    6 => vec![15, 16],
-                    7 => vec![18, 17, 19],
-                    8 => vec![20, 21],
-                    9 => vec![23, 22, 24],
-                    10 => vec![25, 26],
-                    11 => vec![28, 27, 29],
-                    _ => panic!("impossible scenario")
-                };
-
-                candidate.into_iter().map(|x| self.pitch + (x + 31 * dist12_octs)).collect()
-            }
-        }
+        projection::with_table(projection_type, |table| {
+            table.candidates(dist12_semis)
+                .iter()
+                .map(|c| (self.pitch + (c.steps + 31 * dist12_octs), c.prior))
+                .collect()
+        })
     }
 }
 
+/// Rounds `x` so that floating point scores which only differ by accumulated rounding
+/// errors compare as equal
+fn quantize(x: f64) -> i64 {
+    (x * 1e9).round() as i64
+}
+
+/// Distance in cents between `pitch` in 31 edo and `midi_note` in 12 edo
+fn deviation_from_12edo(pitch: Pitch31, midi_note: u7) -> f64 {
+    let cents31 = pitch.to_steps_from_a4() as f64 * 1200.0 / 31.0;
+    let cents12 = (midi_note.as_int() as f64 - 69.0) * 100.0;
+    (cents31 - cents12).abs()
+}
+
+/// Whether an interval of `steps` dieses is a neutral second, third, sixth or seventh
+/// (or an octave displacement of one)
+pub fn is_neutral_interval(steps: i16) -> bool {
+    matches!(steps.rem_euclid(31), 4 | 9 | 22 | 27)
+}
+
 #[derive(Copy, Clone)]
 pub enum AssonanceMetric {
     Pythagorean
 }
 
+impl AssonanceMetric {
+    pub const ALL: [AssonanceMetric; 1] = [AssonanceMetric::Pythagorean];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AssonanceMetric::Pythagorean => "Pythagorean"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "Pythagorean" => Some(AssonanceMetric::Pythagorean),
+            _ => None
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for AssonanceMetric {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for AssonanceMetric {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        AssonanceMetric::from_name(&name).ok_or_else(|| de::Error::custom(format!("unknown assonance metric: {}", name)))
+    }
+}
+
 #[derive(Copy, Clone)]
 pub enum ProjectionType {
     /// U1, P4/5, Maj2/3/6/7 -> Only 1 option
@@ -227,5 +563,234 @@ pub enum ProjectionType {
     /// U1 -> Only 1 option
     /// P4/5, Maj2/3/6/7 -> 3 options: v / natural / ^
     /// m2/3/6/7, dim5 -> 2 options: #/b variants
-    Meantone31KeepUnison
-}
\ No newline at end of file
+    Meantone31KeepUnison,
+
+    /// Same as Meantone31KeepUnison, but 2nds/3rds/6ths/7ths also have the
+    /// neutral interval between the minor and major variant as an option:
+    /// m2/M2 -> n2 (4 steps), m3/M3 -> n3 (9 steps),
+    /// m6/M6 -> n6 (22 steps), m7/M7 -> n7 (27 steps)
+    ///
+    /// See `TonalSpaceConfig::neutral_bias` for how much neutral intervals are favoured.
+    Meantone31Neutral,
+
+    /// A projection table loaded at runtime, see `projection::load_tables`
+    Custom(usize)
+}
+
+impl ProjectionType {
+    /// Finds a built-in or loaded projection type by name
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "Meantone17" => Some(ProjectionType::Meantone17),
+            "Meantone31KeepUnison" => Some(ProjectionType::Meantone31KeepUnison),
+            "Meantone31Neutral" => Some(ProjectionType::Meantone31Neutral),
+            _ => projection::find_custom(name)
+        }
+    }
+
+    /// Name of the projection type, as accepted by `from_name`
+    pub fn name(self) -> String {
+        projection::with_table(self, |table| table.name.clone())
+    }
+
+    /// All builtin projection types, followed by the registered custom ones
+    pub fn all() -> Vec<Self> {
+        let mut all = vec![ProjectionType::Meantone17, ProjectionType::Meantone31KeepUnison,
+                           ProjectionType::Meantone31Neutral];
+        all.extend(projection::custom_types());
+        all
+    }
+
+    /// The preferred spelling of each key of the octave from middle C (60 to 71) relative to C,
+    /// i.e. the candidate with the lowest prior, ignoring any harmonic context
+    pub fn static_mapping(self) -> Vec<Pitch31> {
+        let c4 = Pitch31::new("C4").unwrap();
+        projection::with_table(self, |table| {
+            (0..12).map(|semitones| {
+                let preferred = table.candidates(semitones).iter()
+                    .min_by(|a, b| a.prior.total_cmp(&b.prior))
+                    .expect("projection tables have candidates for every semitone class");
+                c4 + preferred.steps
+            }).collect()
+        })
+    }
+
+    /// The spelling the projection table prefers for `key`, regardless of harmonic context
+    pub fn static_spelling(self, key: u7) -> Pitch31 {
+        let semitones = key.as_int() as i16 - 60;
+        self.static_mapping()[semitones.rem_euclid(12) as usize] + 31 * semitones.div_euclid(12)
+    }
+}
+
+/// Written as its name, see `ProjectionType::from_name`. Custom projection types must be loaded
+/// before they can be read.
+#[cfg(feature = "serde")]
+impl Serialize for ProjectionType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.name())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for ProjectionType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        ProjectionType::from_name(&name).ok_or_else(|| de::Error::custom(format!("unknown projection type: {}", name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn best(ts: &TonalSpace, key: u8, pt: ProjectionType) -> Pitch31 {
+        ts.convert_to_31(u7::from(key), AssonanceMetric::Pythagorean, pt)[0].0
+    }
+
+    #[test]
+    fn tritone_tie_prefers_smallest_deviation() {
+        // F# and Gb are both 6 fifths from C with equal priors,
+        // but F# is closer to 12 edo
+        let ts = TonalSpace::new(TonalSpaceConfig::default());
+        assert_eq!(best(&ts, 66, ProjectionType::Meantone17), Pitch31::new("F#4").unwrap());
+    }
+
+    /// A tonal space where `a` and `b`, played as A4, each score 0 against themselves only
+    fn unison_tie(a: &str, b: &str) -> TonalSpace {
+        let mut ts = TonalSpace::new(TonalSpaceConfig::default());
+        ts.insert(Pitch31::new(a).unwrap(), u7::from(69));
+        ts.insert(Pitch31::new(b).unwrap(), u7::from(69));
+        ts
+    }
+
+    #[test]
+    fn tie_prefers_fewest_accidentals() {
+        // Fx4 and Cb5 are both 6 steps from A4, but Cb5 has one accidental less
+        let ts = unison_tie("Fx4", "Cb5");
+        assert_eq!(best(&ts, 69, ProjectionType::Meantone17), Pitch31::new("Cb5").unwrap());
+        let ts = unison_tie("Cb5", "Fx4");
+        assert_eq!(best(&ts, 69, ProjectionType::Meantone17), Pitch31::new("Cb5").unwrap());
+    }
+
+    #[test]
+    fn tie_prefers_lowest_pitch() {
+        // Ab4 and A#4 are both 2 steps from A4 with one accidental each
+        let ts = unison_tie("Ab4", "A#4");
+        assert_eq!(best(&ts, 69, ProjectionType::Meantone17), Pitch31::new("Ab4").unwrap());
+        let ts = unison_tie("A#4", "Ab4");
+        assert_eq!(best(&ts, 69, ProjectionType::Meantone17), Pitch31::new("Ab4").unwrap());
+    }
+
+    #[test]
+    fn conversion_is_deterministic() {
+        let build = || {
+            let mut ts = TonalSpace::new(TonalSpaceConfig::default());
+            for &key in &[64, 67, 70, 63, 66, 61] {
+                let p = best(&ts, key, ProjectionType::Meantone31KeepUnison);
+                ts.insert(p, u7::from(key));
+            }
+            ts
+        };
+
+        let expected = build().convert_to_31(u7::from(68), AssonanceMetric::Pythagorean, ProjectionType::Meantone31KeepUnison);
+        for _ in 0..20 {
+            let again = build().convert_to_31(u7::from(68), AssonanceMetric::Pythagorean, ProjectionType::Meantone31KeepUnison);
+            assert_eq!(expected, again);
+        }
+    }
+
+    #[test]
+    fn natural_fifth_preferred() {
+        let ts = TonalSpace::new(TonalSpaceConfig::default());
+        assert_eq!(best(&ts, 67, ProjectionType::Meantone31KeepUnison), Pitch31::new("G4").unwrap());
+    }
+
+    #[test]
+    fn diff_oct_clash_counter() {
+        let config = TonalSpaceConfig { diff_oct_clash_threshold: 1, ..TonalSpaceConfig::default() };
+        let mut ts = TonalSpace::new(config);
+
+        // B4 clashes with C4 only when octave displacement is discounted
+        ts.insert(Pitch31::new("B4").unwrap(), u7::from(71));
+        assert_eq!(ts.notes[&Note::C][0].clash_counter, 1);
+
+        ts.insert(Pitch31::new("B2").unwrap(), u7::from(47));
+        assert!(ts.notes[&Note::C].is_empty());
+    }
+
+    #[test]
+    fn state_toml_round_trip() {
+        let config = TonalSpaceConfig { diff_oct_clash_threshold: 1, ..TonalSpaceConfig::default() };
+        let mut ts = TonalSpace::new(config);
+        ts.insert(Pitch31::new("B4").unwrap(), u7::from(71));
+        ts.insert(Pitch31::new("E4").unwrap(), u7::from(64));
+
+        let restored = TonalSpace::from_toml(&ts.to_toml(), config).unwrap();
+        assert_eq!(restored.note_order, vec![Note::E, Note::B, Note::C]);
+        assert_eq!(restored.notes[&Note::C][0].clash_counter, 1);
+        assert_eq!(restored.to_toml(), ts.to_toml());
+
+        assert!(TonalSpace::from_toml("note_order = [\"C\"]\n[[pitches]]\npitch = \"E4\"\nmidi_key = 64\n\
+                                       clash_counter = 0", config).is_err());
+        assert!(TonalSpace::from_toml("note_order = [\"C\", \"C\"]", config).is_err());
+        // Nothing to spell from
+        assert!(TonalSpace::from_toml("note_order = []", config).is_err());
+        assert!(TonalSpace::from_toml("note_order = [\"C\"]", config).is_err());
+    }
+
+    #[test]
+    fn config_from_toml() {
+        let config = TonalSpaceConfig::from_toml("semitone_threshold = 2\norder_precedence_coefficient = 0.5").unwrap();
+        assert_eq!(config.semitone_threshold, 2);
+        assert_eq!(config.order_precedence_coefficient, 0.5);
+        assert_eq!(config.diff_oct_clash_threshold, 0);
+
+        let round_trip = TonalSpaceConfig::from_toml(&config.to_toml()).unwrap();
+        assert_eq!(round_trip.order_precedence_coefficient, 0.5);
+        assert_eq!(round_trip.semitone_threshold, 2);
+
+        assert!(TonalSpaceConfig::from_toml("semitone_treshold = 2").is_err());
+        assert!(TonalSpaceConfig::from_toml("diff_oct_clash_threshold = -1").is_err());
+
+        // A rejected value leaves the config as it was
+        let mut config = TonalSpaceConfig::default();
+        assert!(config.set("semitone_threshold", "16").is_err());
+        assert!(config.set("order_precedence_coefficient", "-0.5").is_err());
+        assert_eq!(config.semitone_threshold, 3);
+        assert_eq!(config.order_precedence_coefficient, 0.99);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let pt = ProjectionType::Meantone31KeepUnison;
+        let mut ts = TonalSpace::new(TonalSpaceConfig { semitone_threshold: 2, ..TonalSpaceConfig::default() });
+        for key in [64, 67, 63, 70] {
+            let pitch = best(&ts, key, pt);
+            ts.insert(pitch, u7::from(key));
+        }
+
+        let saved = toml::to_string(&toml::Value::try_from(&ts).unwrap()).unwrap();
+        assert!(saved.contains("pitch = \"G4\""), "{}", saved);
+        let restored: TonalSpace = toml::from_str(&saved).unwrap();
+        assert_eq!(restored.note_order, ts.note_order);
+        assert_eq!(restored.config.semitone_threshold, 2);
+        assert_eq!(restored.mapping(AssonanceMetric::Pythagorean, pt), ts.mapping(AssonanceMetric::Pythagorean, pt));
+
+        #[derive(Serialize, Deserialize)]
+        struct Settings {
+            projection: ProjectionType,
+            metric: AssonanceMetric
+        }
+        let settings: Settings = toml::from_str("projection = \"Meantone31Neutral\"\nmetric = \"Pythagorean\"").unwrap();
+        assert_eq!(settings.projection.name(), "Meantone31Neutral");
+        assert!(toml::from_str::<Settings>("projection = \"Nope\"\nmetric = \"Pythagorean\"").is_err());
+
+        // Restored states and configs are checked like those read from TOML
+        assert!(toml::from_str::<TonalSpace>("note_order = []\n[notes]\n[config]").is_err());
+        assert!(toml::from_str::<TonalSpace>(&saved.replace("pitch = \"G4\"", "pitch = \"A4\"")).is_err());
+        assert!(toml::from_str::<TonalSpaceConfig>("semitone_threshold = 20").is_err());
+        assert!(toml::from_str::<TonalSpaceConfig>("semitone_treshold = 2").is_err());
+        assert_eq!(toml::from_str::<TonalSpaceConfig>("neutral_bias = 3.0").unwrap().semitone_threshold, 3);
+    }
+}
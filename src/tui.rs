@@ -0,0 +1,252 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+use crate::processor::Processor;
+use crate::theory::{Note, Pitch31};
+
+/// Lines of the scrolling log kept on screen
+const LOG_LINES: usize = 15;
+
+/// Width of a note on the chain of fifths, in characters
+const CELL: usize = 4;
+
+/// Candidates listed for the last key
+const SHOWN_CANDIDATES: usize = 6;
+
+// Greys of the 256 colour palette (which run from 232, darkest, to 255) of the oldest and the most
+// recent member of the tonal space
+const OLDEST_GREY: u8 = 236;
+const NEWEST_GREY: u8 = 255;
+
+// ANSI escape sequences
+const ALTERNATE_SCREEN: &str = "\x1b[?1049h\x1b[?25l";
+const MAIN_SCREEN: &str = "\x1b[?25h\x1b[?1049l";
+const CLEAR: &str = "\x1b[H\x1b[2J";
+const BOLD: &str = "\x1b[1m";
+const SOUNDING: &str = "\x1b[1;4;32m";
+const RESET: &str = "\x1b[0m";
+
+lazy_static! {
+    /// Lines reported while the terminal UI is running that it has not shown yet, or None when it is not running
+    static ref REPORTED: Mutex<Option<Vec<String>>> = Mutex::new(None);
+}
+
+/// Writes an error or status line to stderr, or to the log of the terminal UI while it is running,
+/// as anything written to the terminal would garble its screen
+pub fn report(line: String) {
+    match REPORTED.lock().unwrap().as_mut() {
+        Some(reported) => reported.push(line),
+        None => eprintln!("{}", line)
+    }
+}
+
+/// What the terminal UI shows of the processor after a message from `input`
+pub struct View {
+    pub input: usize,
+
+    /// Pitches of the tonal space of `input` with their recency weight, see `TonalSpace::members`
+    pub members: Vec<(Pitch31, f64)>,
+
+    pub sounding: Vec<Pitch31>,
+
+    /// The key spelled last, with its candidates and their scores, best first
+    pub candidates: Option<(u8, Vec<(Pitch31, f64)>)>
+}
+
+impl View {
+    pub fn of(processor: &Processor, input: usize) -> Self {
+        View {
+            input,
+            members: processor.tonal_space(input).members(),
+            sounding: processor.sounding(),
+            candidates: processor.last_candidates().map(|(key, c)| (key.as_int(), c.clone()))
+        }
+    }
+}
+
+/// Full screen view of live mode, drawn with ANSI escape sequences on the alternate screen,
+/// which is left again when the UI is dropped. Lines passed to `report` meanwhile are shown in its log.
+pub struct Tui {
+    log: VecDeque<String>
+}
+
+impl Tui {
+    pub fn start() -> Self {
+        print!("{}", ALTERNATE_SCREEN);
+        *REPORTED.lock().unwrap() = Some(vec![]);
+        Tui { log: VecDeque::new() }
+    }
+
+    /// Adds a line to the scrolling log
+    pub fn log(&mut self, line: String) {
+        if self.log.len() == LOG_LINES {
+            self.log.pop_front();
+        }
+        self.log.push_back(line);
+    }
+
+    pub fn draw(&mut self, view: &View) {
+        let reported = REPORTED.lock().unwrap().as_mut().map(std::mem::take).unwrap_or_default();
+        for line in reported {
+            self.log(line);
+        }
+
+        let mut stdout = io::stdout().lock();
+        write!(stdout, "{}{}", CLEAR, frame(view, &self.log))
+            .and_then(|_| stdout.flush())
+            .unwrap_or_else(|e| report(format!("Error when drawing: {}", e)));
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        print!("{}", MAIN_SCREEN);
+        io::stdout().flush().ok();
+        // Lines reported since the last draw would not be seen otherwise
+        for line in REPORTED.lock().unwrap().take().unwrap_or_default() {
+            eprintln!("{}", line);
+        }
+    }
+}
+
+/// The 31 notes from 15 fifths below D to 15 fifths above it
+fn chain_of_fifths() -> Vec<Note> {
+    let mut notes = (0..31).map(Note::from).collect::<Vec<_>>();
+    notes.sort_by_key(|n| n.chain_position());
+    notes
+}
+
+/// Draws the chain of fifths with the members of the tonal space shaded by recency (brighter is more
+/// recent) and the sounding notes underlined, followed by the candidates of the last key and the log.
+/// The shades span the weights of the members, which are all close to 1 when they decay slowly.
+pub fn frame(view: &View, log: &VecDeque<String>) -> String {
+    let mut frame = format!("{}31 from 12: tonal space of input {} on the chain of fifths{}\n\n", BOLD, view.input, RESET);
+    let newest = view.members.iter().map(|(_, w)| *w).fold(f64::NEG_INFINITY, f64::max);
+    let oldest = view.members.iter().map(|(_, w)| *w).fold(f64::INFINITY, f64::min);
+
+    for note in chain_of_fifths() {
+        let weight = view.members.iter()
+            .filter(|(p, _)| p.note == note)
+            .map(|(_, w)| *w)
+            .fold(None, |max: Option<f64>, w| Some(max.map_or(w, |m| m.max(w))));
+        // Ex is written in the octave numbering of the enum, but sits 15 fifths below D as Gbb
+        let name = match note {
+            Note::ExGbb => "Gbb".to_owned(),
+            note => note.to_string()
+        };
+        let name = format!("{:^width$}", name, width = CELL);
+        let style = if view.sounding.iter().any(|p| p.note == note) { SOUNDING } else { "" };
+
+        match weight {
+            Some(w) => {
+                let recency = if newest > oldest { (w - oldest) / (newest - oldest) } else { 1.0 };
+                let grey = OLDEST_GREY + (recency * (NEWEST_GREY - OLDEST_GREY) as f64).round() as u8;
+                let fg = if grey >= 244 { 16 } else { 255 };
+                frame.push_str(&format!("\x1b[48;5;{};38;5;{}m{}{}{}", grey, fg, style, name, RESET));
+            }
+            None => frame.push_str(&format!("{}{}{}", style, name, RESET))
+        }
+    }
+    frame.push_str("\n\n");
+
+    let members = view.members.iter().map(|(p, w)| format!("{} {:.2}", p, w)).collect::<Vec<_>>();
+    frame.push_str(&format!("Tonal space: {}\n", members.join(", ")));
+    let sounding = view.sounding.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+    frame.push_str(&format!("Sounding:    {}\n", sounding.join(" ")));
+    if let Some((key, candidates)) = &view.candidates {
+        let scores = candidates.iter()
+            .take(SHOWN_CANDIDATES)
+            .map(|(p, score)| format!("{} {:.2}", p, score))
+            .collect::<Vec<_>>();
+        frame.push_str(&format!("Key {:<3}     {}{}{}\n", key, BOLD, scores.join(", "), RESET));
+    }
+
+    frame.push_str(&format!("\n{}Log{} (enter 'save NAME' or 'recall NAME' for presets, an empty line to exit)\n",
+                            BOLD, RESET));
+    for line in log {
+        frame.push_str(line);
+        frame.push('\n');
+    }
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strip_escapes(s: &str) -> String {
+        let mut plain = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                chars.by_ref().find(|c| c.is_ascii_alphabetic());
+            } else {
+                plain.push(c);
+            }
+        }
+        plain
+    }
+
+    #[test]
+    fn frame_shows_tonal_space_and_candidates() {
+        let pitch = |name| Pitch31::new(name).unwrap();
+        let view = View {
+            input: 0,
+            members: vec![(pitch("E4"), 1.0), (pitch("C4"), 0.5)],
+            sounding: vec![pitch("C4"), pitch("E4")],
+            candidates: Some((64, vec![(pitch("E4"), 1.5), (pitch("Fb4"), 4.25)]))
+        };
+        let mut log = VecDeque::new();
+        log.push_back("0 -> E4".to_owned());
+
+        let frame = frame(&view, &log);
+        let lines = strip_escapes(&frame).lines().map(str::to_owned).collect::<Vec<_>>();
+        let chain = lines[2].split_whitespace().collect::<Vec<_>>();
+        assert_eq!(chain.len(), 31);
+        assert_eq!(&chain[13..18], &["C", "G", "D", "A", "E"]);
+        assert_eq!((chain[0], chain[30]), ("Gbb", "Ax"));
+        assert_eq!(lines[4], "Tonal space: E4 1.00, C4 0.50");
+        assert_eq!(lines[6], "Key 64      E4 1.50, Fb4 4.25");
+        assert_eq!(lines.last().unwrap(), "0 -> E4");
+
+        // The most recent member is drawn brightest, notes outside the tonal space are not shaded
+        assert!(frame.contains("\x1b[48;5;255;38;5;16m\x1b[1;4;32m E  "));
+        assert!(frame.contains("\x1b[48;5;236;38;5;255m\x1b[1;4;32m C  "));
+        assert!(frame.contains(&format!("{} G  ", RESET)));
+    }
+
+    #[test]
+    fn reported_lines_go_to_the_log() {
+        let mut tui = Tui::start();
+        report("Saved preset 'verse'".to_owned());
+        tui.draw(&View { input: 0, members: vec![], sounding: vec![], candidates: None });
+        assert!(tui.log.contains(&"Saved preset 'verse'".to_owned()));
+        drop(tui);
+        assert!(REPORTED.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn shades_span_member_weights() {
+        // Weights of four notes with the default decay of 0.99
+        let names = ["D4", "A4", "E4", "B4"];
+        let view = View {
+            input: 0,
+            members: names.iter().zip(vec![1.0, 0.99, 0.9801, 0.970299])
+                .map(|(n, w)| (Pitch31::new(n).unwrap(), w))
+                .collect(),
+            sounding: vec![],
+            candidates: None
+        };
+        let frame = frame(&view, &VecDeque::new());
+        let grey = |name: &str| {
+            let cell = frame.find(&format!("m{:^width$}", name, width = CELL)).unwrap();
+            let start = frame[..cell].rfind("48;5;").unwrap() + 5;
+            frame[start..].split(';').next().unwrap().parse::<u8>().unwrap()
+        };
+        let greys = names.iter().map(|n| grey(&n[..1])).collect::<Vec<_>>();
+        assert_eq!(greys, vec![255, 249, 242, 236]);
+    }
+}